@@ -0,0 +1,24 @@
+//! translates the programs with `toyvm::aot::rust`, as the build script of
+//! an embedder would
+
+use std::{env, fs};
+
+#[allow(dead_code)]
+#[path = "../src/tests/programs.rs"]
+mod programs;
+
+fn main() {
+    println!("cargo::rerun-if-changed=../src/tests/programs.rs");
+    let out = env::var("OUT_DIR").unwrap();
+    for program in programs::PROGRAMS {
+        let entries: Vec<(&str, usize)> = program.cases.iter().map(|(n, a, _)| (*n, *a)).collect();
+        let mut code = toyvm::aot::rust::translate(program.code, 16, &entries);
+        // the generated functions by name, for the tests
+        code += "\npub const COMPILED: crate::Compiled = &[\n";
+        for (name, _) in &entries {
+            code += &format!("    (\"{name}\", {name}),\n");
+        }
+        code += "];\n";
+        fs::write(format!("{out}/{}.rs", program.name), code).unwrap();
+    }
+}
@@ -0,0 +1,33 @@
+//! checks the code `toyvm::aot::rust` generates from a build script against the interpreter
+
+#[path = "../../src/tests/programs.rs"]
+pub mod programs;
+
+#[cfg(test)]
+mod tests;
+
+use toyvm::{Result, VM};
+
+type Compiled = &'static [(&'static str, fn(&mut VM) -> Result<()>)];
+
+macro_rules! compiled {
+    ($($program:ident),*) => {
+        $(
+            pub mod $program {
+                include!(concat!(env!("OUT_DIR"), "/", stringify!($program), ".rs"));
+            }
+        )*
+
+        /// the functions generated for the program `name`
+        pub fn compiled(name: &str) -> Compiled {
+            match name {
+                $(stringify!($program) => $program::COMPILED,)*
+                _ => panic!("no program {name}"),
+            }
+        }
+    };
+}
+
+compiled!(
+    basics, throws, scope, table, jump_out, locals, globals, indirect, bits, loads, arith, regions
+);
@@ -0,0 +1,49 @@
+use crate::{compiled, programs::PROGRAMS};
+use toyvm::{Result, VM};
+
+const MEMSIZE: usize = 0x4000;
+const PSTACK: usize = 0x2000;
+const RSTACK: usize = 0x3FFC;
+
+fn create_vm(code: &[u8], setup: fn(&mut VM), args: &[i32]) -> VM {
+    let mut vm = VM::new(vec![0; MEMSIZE], Vec::new(), 0, 4);
+    vm.write_i32(PSTACK as i32, 0);
+    vm.write_i32(RSTACK as i32, 4);
+    vm.add_function(&|vm: &mut VM| {
+        let a = vm.pop_i32();
+        vm.push_i32(a.wrapping_mul(2));
+    });
+    setup(&mut vm);
+    vm.write(16, code);
+    for arg in args {
+        vm.push_i32(*arg);
+    }
+    vm
+}
+
+// the result, errors with their context, the pstack and the cell at 0x100
+fn outcome(vm: &mut VM, result: Result<()>) -> String {
+    let mut line = format!("{result:?}");
+    while vm.read_i32(0) != PSTACK as i32 {
+        line += &format!(" {}", vm.pop_i32());
+    }
+    line + &format!(" {}", vm.read_i32(0x100))
+}
+
+#[test]
+fn test_compiled_matches_interpreter() {
+    for program in PROGRAMS {
+        let compiled = compiled(program.name);
+        assert_eq!(program.cases.len(), compiled.len());
+        for ((name, entry, args), (_, f)) in program.cases.iter().zip(compiled) {
+            let mut vm = create_vm(program.code, program.setup, args);
+            let mut ip = *entry;
+            let result = vm.run(&mut ip);
+            let expected = outcome(&mut vm, result);
+
+            let mut vm = create_vm(program.code, program.setup, args);
+            let result = f(&mut vm);
+            assert_eq!(expected, outcome(&mut vm, result), "{name}");
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! times a hot loop through `step`, `run` and `run` with the code cache:
+//!
+//! ```text
+//! cargo bench --bench dispatch
+//! ```
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use toyvm::{VM, opcode::*};
+
+const ITERATIONS: i32 = 1_000_000;
+
+// ( n -- ), adds n + (n - 1) + .. + 1 into the cell at 0x100
+const SUM_LOOP: [u8; 28] = [
+    DUP,  // 16
+    BRZI, // 17
+    42, 0, 0, 0,         // 18 - 21
+    DUP,       // 22
+    I32_CONST, // 23
+    0, 1, 0, 0,         // 24 - 27
+    I32_LOAD,  // 28
+    ADD,       // 29
+    I32_CONST, // 30
+    0, 1, 0, 0,         // 31 - 34
+    I32_STORE, // 35
+    DEC,       // 36
+    BRI,       // 37
+    16, 0, 0, 0,    // 38 - 41
+    DROP, // 42
+    END,  // 43
+];
+
+fn create_vm() -> VM {
+    let mut vm = VM::new(vec![0; 0x4000], Vec::new(), 0, 4);
+    vm.write_i32(0x2000, 0);
+    vm.write_i32(0x3ffc, 4);
+    vm.write(16, &SUM_LOOP);
+    vm
+}
+
+fn time(name: &str, baseline: Option<Duration>, f: impl Fn(&mut VM)) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..5 {
+        let mut vm = create_vm();
+        vm.push_i32(ITERATIONS);
+        let start = Instant::now();
+        f(black_box(&mut vm));
+        best = best.min(start.elapsed());
+        assert_eq!(
+            (ITERATIONS as i64 * (ITERATIONS as i64 + 1) / 2) as i32,
+            vm.read_i32(0x100)
+        );
+    }
+    // 10 instructions per iteration
+    let per_instr = best.as_nanos() as f64 / (10.0 * ITERATIONS as f64);
+    print!("{name:<12} {best:>10.2?} {per_instr:>6.2} ns/instr");
+    match baseline {
+        Some(baseline) => println!("  {:.1}x", baseline.as_secs_f64() / best.as_secs_f64()),
+        None => println!(),
+    }
+    best
+}
+
+fn main() {
+    let step = time("step", None, |vm| {
+        let mut ip = 16;
+        while vm.step(&mut ip).unwrap() {}
+    });
+    time("run", Some(step), |vm| vm.run(&mut 16).unwrap());
+    time("code cache", Some(step), |vm| {
+        vm.enable_code_cache(16, SUM_LOOP.len());
+        vm.run(&mut 16).unwrap();
+    });
+}
@@ -0,0 +1,469 @@
+//! control-flow and call graphs of code, exportable as Graphviz DOT, and the
+//! pstack effects of its functions
+//!
+//! ```ignore
+//! let cfg = Cfg::new(vm.memory_ref(), 0x10, 0x80, &[0x10]);
+//! std::fs::write("cfg.dot", cfg.cfg_dot()).unwrap();
+//! ```
+
+use crate::{
+    Signature,
+    decode::{Instruction, decode_range, table},
+    opcode,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    /// control continues at the address
+    To(usize),
+    /// the target is computed at run time (`BR`, `BRZ`, `JMP`, `JZ`, `CALL`, `TAIL_CALL`,
+    /// `CALL_INDIRECT`)
+    Unresolved,
+}
+
+#[derive(Debug)]
+pub struct Block {
+    pub start: usize,
+    pub instrs: Vec<Instruction>,
+    /// where control goes after the block, a call's return address for calls
+    pub successors: Vec<Edge>,
+    /// the function called at the end of the block
+    pub call: Option<Edge>,
+}
+
+impl Block {
+    /// address after the last instruction
+    pub fn end(&self) -> usize {
+        self.instrs.last().map_or(self.start, |instr| instr.next())
+    }
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub entry: usize,
+    /// starts of the blocks reachable from `entry` without following calls
+    pub blocks: BTreeSet<usize>,
+    /// callees, in address order of the calls
+    pub calls: Vec<Edge>,
+}
+
+#[derive(Debug)]
+pub struct Cfg {
+    /// every block of the range, by start address
+    pub blocks: BTreeMap<usize, Block>,
+    /// functions reachable from the entries through static calls, by entry
+    pub functions: BTreeMap<usize, Function>,
+}
+
+/// net pstack effect of a function, displayed in Forth style, e.g. `( a b -- c )`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackEffect {
+    pub params: usize,
+    pub results: usize,
+    /// most items on the pstack at any point, the parameters included
+    pub max_depth: usize,
+}
+
+impl fmt::Display for StackEffect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |i: usize| match u8::try_from(i) {
+            Ok(i) if i < 26 => char::from(b'a' + i).to_string(),
+            _ => format!("x{i}"),
+        };
+        write!(f, "(")?;
+        for i in 0..self.params {
+            write!(f, " {}", name(i))?;
+        }
+        write!(f, " --")?;
+        for i in 0..self.results {
+            write!(f, " {}", name(self.params + i))?;
+        }
+        write!(f, " )")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    /// paths reaching `addr` disagree on the depth, relative to the function's entry
+    Mismatch { addr: usize, depths: (isize, isize) },
+    /// the effect of the instruction at the address is not statically known
+    Unknown(usize),
+    /// the function at the address calls itself, directly or not
+    Recursive(usize),
+}
+
+// ends a basic block
+pub(crate) fn is_control(op: u8) -> bool {
+    matches!(
+        op,
+        opcode::UNREACHABLE
+            | opcode::END
+            | opcode::BRI
+            | opcode::BRZI
+            | opcode::BR
+            | opcode::BRZ
+            | opcode::JMP
+            | opcode::JZ
+            | opcode::JMPI
+            | opcode::JZI
+            | opcode::RETURN
+            | opcode::CALL
+            | opcode::CALLI
+            | opcode::CALL_INDIRECT
+            | opcode::TAIL_CALL
+            | opcode::TAIL_CALLI
+            | opcode::BR_TABLE
+            | opcode::CATCH
+            | opcode::THROW
+    )
+}
+
+impl Cfg {
+    /// splits `memory[from..to]` into basic blocks. `entries` are the functions
+    /// the call graph starts from
+    pub fn new(memory: &[u8], from: usize, to: usize, entries: &[usize]) -> Self {
+        let instrs = decode_range(memory, from, to);
+        let mut leaders: BTreeSet<usize> = entries.iter().copied().collect();
+        leaders.insert(from);
+        for instr in &instrs {
+            leaders.extend(instr.target());
+            leaders.extend(table(memory, instr));
+            if is_control(instr.op) {
+                leaders.insert(instr.next());
+            }
+        }
+
+        let mut blocks = BTreeMap::new();
+        let mut block: Vec<Instruction> = Vec::new();
+        for (i, instr) in instrs.iter().enumerate() {
+            block.push(*instr);
+            let last = instrs
+                .get(i + 1)
+                .is_none_or(|next| leaders.contains(&next.addr));
+            if last {
+                let block = Self::block(memory, std::mem::take(&mut block));
+                blocks.insert(block.start, block);
+            }
+        }
+
+        let mut functions = BTreeMap::new();
+        let mut work: Vec<usize> = entries.iter().rev().copied().collect();
+        while let Some(entry) = work.pop() {
+            if functions.contains_key(&entry) {
+                continue;
+            }
+            let function = Self::function(&blocks, entry);
+            for call in &function.calls {
+                if let Edge::To(callee) = call {
+                    work.push(*callee);
+                }
+            }
+            functions.insert(entry, function);
+        }
+        Cfg { blocks, functions }
+    }
+
+    fn block(memory: &[u8], instrs: Vec<Instruction>) -> Block {
+        let last = *instrs.last().unwrap();
+        let next = Edge::To(last.next());
+        let target = last.target().map_or(Edge::Unresolved, Edge::To);
+        let (successors, call) = match last.op {
+            opcode::UNREACHABLE | opcode::END | opcode::RETURN => (vec![], None),
+            opcode::BRI | opcode::JMPI | opcode::BR | opcode::JMP => (vec![target], None),
+            opcode::BRZI | opcode::JZI | opcode::BRZ | opcode::JZ => (vec![target, next], None),
+            // the handler is entered by a later `THROW`
+            opcode::CATCH => (vec![next, target], None),
+            opcode::CALLI | opcode::CALL | opcode::CALL_INDIRECT => (vec![next], Some(target)),
+            // the callee returns for the caller
+            opcode::TAIL_CALLI | opcode::TAIL_CALL => (vec![], Some(target)),
+            opcode::BR_TABLE => {
+                let mut targets = table(memory, &last);
+                targets.sort_unstable();
+                targets.dedup();
+                (targets.into_iter().map(Edge::To).collect(), None)
+            }
+            _ => (vec![next], None),
+        };
+        Block {
+            start: instrs[0].addr,
+            instrs,
+            successors,
+            call,
+        }
+    }
+
+    fn function(blocks: &BTreeMap<usize, Block>, entry: usize) -> Function {
+        let mut reachable = BTreeSet::new();
+        let mut work = vec![entry];
+        while let Some(start) = work.pop() {
+            let Some(block) = blocks.get(&start) else {
+                continue;
+            };
+            if !reachable.insert(start) {
+                continue;
+            }
+            for edge in &block.successors {
+                if let Edge::To(addr) = edge {
+                    work.push(*addr);
+                }
+            }
+        }
+        let calls = reachable
+            .iter()
+            .filter_map(|start| blocks[start].call)
+            .collect();
+        Function {
+            entry,
+            blocks: reachable,
+            calls,
+        }
+    }
+
+    /// infers the pstack effect of every function. `host[i]` is the `(pops, pushes)`
+    /// of host function `i`, known where `CALL_VM` follows an `I32_CONST`
+    pub fn stack_effects(
+        &self,
+        host: &[(usize, usize)],
+    ) -> BTreeMap<usize, Result<StackEffect, StackError>> {
+        let mut effects = BTreeMap::new();
+        for entry in self.functions.keys() {
+            // memoized in `effects`
+            let _ = self.stack_effect(*entry, host, &mut effects, &mut BTreeSet::new());
+        }
+        effects
+    }
+
+    fn stack_effect(
+        &self,
+        entry: usize,
+        host: &[(usize, usize)],
+        effects: &mut BTreeMap<usize, Result<StackEffect, StackError>>,
+        active: &mut BTreeSet<usize>,
+    ) -> Result<StackEffect, StackError> {
+        if let Some(effect) = effects.get(&entry) {
+            return *effect;
+        }
+        if !active.insert(entry) {
+            return Err(StackError::Recursive(entry));
+        }
+        let result = self.infer(entry, host, effects, active);
+        active.remove(&entry);
+        effects.insert(entry, result);
+        result
+    }
+
+    // walks the blocks of the function at `entry` tracking the depth relative to the entry
+    fn infer(
+        &self,
+        entry: usize,
+        host: &[(usize, usize)],
+        effects: &mut BTreeMap<usize, Result<StackEffect, StackError>>,
+        active: &mut BTreeSet<usize>,
+    ) -> Result<StackEffect, StackError> {
+        if !self.blocks.contains_key(&entry) {
+            return Err(StackError::Unknown(entry));
+        }
+        let function = &self.functions[&entry];
+        let mut depths: BTreeMap<usize, isize> = BTreeMap::from([(entry, 0)]);
+        let mut exit: Option<isize> = None;
+        let (mut min, mut max) = (0, 0);
+        let mut work = vec![entry];
+        while let Some(start) = work.pop() {
+            let Some(block) = self.blocks.get(&start) else {
+                continue;
+            };
+            let mut depth = depths[&start];
+            max = max.max(depth);
+            let mut prev: Option<&Instruction> = None;
+            for instr in &block.instrs {
+                let (pops, pushes) = match (instr.op, prev) {
+                    (opcode::CALLI | opcode::TAIL_CALLI, _) => {
+                        let callee = instr.target().unwrap();
+                        // outside the analysed range
+                        if !self.blocks.contains_key(&callee) {
+                            return Err(StackError::Unknown(instr.addr));
+                        }
+                        let effect = self.stack_effect(callee, host, effects, active)?;
+                        max = max.max(depth - effect.params as isize + effect.max_depth as isize);
+                        (effect.params, effect.results)
+                    }
+                    (opcode::CALL_INDIRECT, _) => {
+                        // the signature is checked, the callee's depth is not known
+                        let signature = Signature::decode(instr.imm_i32());
+                        let params = usize::from(signature.params);
+                        (params + 1, usize::from(signature.results))
+                    }
+                    (opcode::CALL_VM, Some(prev)) if prev.op == opcode::I32_CONST => {
+                        let effect = host.get(prev.imm_i32() as usize);
+                        // pops the index pushed by the constant, too
+                        effect
+                            .map(|(pops, pushes)| (pops + 1, *pushes))
+                            .ok_or(StackError::Unknown(instr.addr))?
+                    }
+                    (op, _) => opcode::stack_effect(op).ok_or(StackError::Unknown(instr.addr))?,
+                };
+                min = min.min(depth - pops as isize);
+                depth += pushes as isize - pops as isize;
+                max = max.max(depth);
+                if matches!(instr.op, opcode::END | opcode::RETURN | opcode::TAIL_CALLI) {
+                    match exit {
+                        Some(other) if other != depth => {
+                            let depths = (other, depth);
+                            return Err(StackError::Mismatch {
+                                addr: instr.addr,
+                                depths,
+                            });
+                        }
+                        _ => exit = Some(depth),
+                    }
+                }
+                prev = Some(instr);
+            }
+            let last = block.instrs.last().unwrap();
+            if block.call == Some(Edge::Unresolved) && last.op != opcode::CALL_INDIRECT {
+                return Err(StackError::Unknown(last.addr));
+            }
+            for edge in &block.successors {
+                let Edge::To(next) = *edge else {
+                    return Err(StackError::Unknown(last.addr));
+                };
+                // a handler starts with the stack of its `CATCH` and the thrown code
+                let depth = if last.op == opcode::CATCH && Some(next) == last.target() {
+                    depth + 1
+                } else {
+                    depth
+                };
+                if !function.blocks.contains(&next) {
+                    continue;
+                }
+                match depths.get(&next) {
+                    Some(other) if *other != depth => {
+                        let depths = (*other, depth);
+                        return Err(StackError::Mismatch { addr: next, depths });
+                    }
+                    Some(_) => {}
+                    None => {
+                        depths.insert(next, depth);
+                        work.push(next);
+                    }
+                }
+            }
+        }
+        let params = -min;
+        Ok(StackEffect {
+            params: params as usize,
+            results: (params + exit.unwrap_or(0)) as usize,
+            max_depth: (params + max) as usize,
+        })
+    }
+
+    /// the control-flow graph, blocks clustered by function
+    pub fn cfg_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph cfg {{").unwrap();
+        writeln!(out, "    node [shape=box, fontname=monospace];").unwrap();
+        let mut clustered = BTreeSet::new();
+        for function in self.functions.values() {
+            writeln!(out, "    subgraph cluster_{:x} {{", function.entry).unwrap();
+            writeln!(out, "        label=\"fn {:#x}\";", function.entry).unwrap();
+            for start in &function.blocks {
+                // blocks shared by several functions are drawn in the first one
+                if clustered.insert(*start) {
+                    writeln!(out, "        b_{start:x};").unwrap();
+                }
+            }
+            writeln!(out, "    }}").unwrap();
+        }
+        for block in self.blocks.values() {
+            let mut label = format!("{:#x}:\\l", block.start);
+            for instr in &block.instrs {
+                write!(label, "  {instr}\\l").unwrap();
+            }
+            writeln!(out, "    b_{:x} [label=\"{label}\"];", block.start).unwrap();
+        }
+        let mut unresolved = false;
+        let mut outside = BTreeSet::new();
+        for block in self.blocks.values() {
+            for edge in &block.successors {
+                let to = match edge {
+                    Edge::To(addr) if self.blocks.contains_key(addr) => format!("b_{addr:x}"),
+                    Edge::To(addr) => {
+                        outside.insert(*addr);
+                        format!("b_{addr:x}")
+                    }
+                    Edge::Unresolved => {
+                        unresolved = true;
+                        "unresolved".to_string()
+                    }
+                };
+                let style = if *edge == Edge::Unresolved {
+                    " [style=dashed]"
+                } else {
+                    ""
+                };
+                writeln!(out, "    b_{:x} -> {to}{style};", block.start).unwrap();
+            }
+        }
+        // targets outside the range
+        for addr in outside {
+            writeln!(
+                out,
+                "    b_{addr:x} [label=\"{addr:#x}\", shape=plaintext];"
+            )
+            .unwrap();
+        }
+        if unresolved {
+            Self::unresolved_node(&mut out);
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    /// the call graph of the functions reachable from the entries
+    pub fn call_graph_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph calls {{").unwrap();
+        writeln!(out, "    node [shape=box, fontname=monospace];").unwrap();
+        for entry in self.functions.keys() {
+            writeln!(out, "    f_{entry:x} [label=\"fn {entry:#x}\"];").unwrap();
+        }
+        let mut unresolved = false;
+        for function in self.functions.values() {
+            // one edge per callee
+            let mut callees: Vec<Edge> = Vec::new();
+            for call in &function.calls {
+                if !callees.contains(call) {
+                    callees.push(*call);
+                }
+            }
+            for callee in callees {
+                match callee {
+                    Edge::To(addr) => {
+                        writeln!(out, "    f_{:x} -> f_{addr:x};", function.entry).unwrap();
+                    }
+                    Edge::Unresolved => {
+                        unresolved = true;
+                        writeln!(
+                            out,
+                            "    f_{:x} -> unresolved [style=dashed];",
+                            function.entry
+                        )
+                        .unwrap();
+                    }
+                }
+            }
+        }
+        if unresolved {
+            Self::unresolved_node(&mut out);
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    // sink for computed targets
+    fn unresolved_node(out: &mut String) {
+        writeln!(out, "    unresolved [label=\"?\", shape=circle];").unwrap();
+    }
+}
@@ -0,0 +1,6 @@
+//! ahead-of-time translation of bytecode images
+
+pub mod c;
+pub mod rust;
+
+pub use crate::vm::rt;
@@ -0,0 +1,649 @@
+//! translation of a code image into a standalone C file.
+//!
+//! the generated code works on the same memory layout as `VM`: one byte array,
+//! pstack and rstack living in memory with their pointers in cells.
+//! `CALL_VM` goes through a table of host functions.
+//! memory regions, the private return stack, unknown op handlers, the code cache
+//! and the jit have no equivalent. control reaching code outside the image
+//! stops with `TOYVM_NOT_TRANSLATED`, where the interpreter panics the
+//! generated code stops with `TOYVM_PANIC`. so does a `CATCH` nested deeper
+//! than `TOYVM_MAX_CATCH` and an `ENTER` beyond `TOYVM_MAX_FRAMES`. the locals
+//! are limited to `TOYVM_MAX_LOCALS`, the vm's default limit
+
+use crate::{
+    decode::{decode, table},
+    opcode,
+};
+use std::fmt::Write;
+
+const PRELUDE: &str = r#"#include <setjmp.h>
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+
+typedef struct toyvm toyvm;
+typedef void (*toyvm_fn)(toyvm *vm);
+
+#define TOYVM_MAX_CATCH 64
+#define TOYVM_MAX_FRAMES 256
+#define TOYVM_MAX_LOCALS 0x10000
+
+typedef struct {
+    uint32_t addr; /* handler */
+    uint32_t psp;
+    uint32_t rsp;
+    size_t frames_len;
+    size_t locals_len;
+} toyvm_catch_frame;
+
+typedef struct {
+    int64_t value; /* sign-extended for i32 globals */
+    uint8_t is_i64;
+    uint8_t is_mutable;
+} toyvm_global;
+
+typedef struct {
+    uint32_t entry;
+    uint32_t signature; /* encoded as the immediate of CALL_INDIRECT */
+} toyvm_table_entry;
+
+struct toyvm {
+    uint8_t *memory; /* malloc'ed, MEMORY_GROW reallocs it */
+    size_t memory_len;
+    size_t max_pages;
+    uint32_t pstack_top; /* address of the cell holding the pstack pointer */
+    uint32_t rstack_top; /* address of the cell holding the rstack pointer */
+    toyvm_fn *functions; /* CALL_VM table */
+    size_t functions_len;
+    uint32_t ip;       /* ip after the last toyvm_run, as VM::run leaves it */
+    uint32_t trap_arg; /* function index for TOYVM_UNKNOWN_VM_FN, opcode for TOYVM_UNKNOWN_OP,
+                          code for TOYVM_UNCAUGHT, index for TOYVM_BAD_LOCAL, the globals and the table */
+    toyvm_catch_frame catch_frames[TOYVM_MAX_CATCH]; /* installed by CATCH */
+    size_t catch_depth;
+    size_t frames[TOYVM_MAX_FRAMES]; /* set up by ENTER, where their locals start */
+    size_t frames_len;
+    int32_t locals[TOYVM_MAX_LOCALS];
+    size_t locals_len;
+    toyvm_global *globals; /* declared by the host, as with VM::add_global */
+    size_t globals_len;
+    toyvm_table_entry *table; /* for CALL_INDIRECT, as with VM::add_table_entry */
+    size_t table_len;
+};
+
+enum {
+    TOYVM_OK = 0,
+    TOYVM_UNREACHABLE = 1,
+    TOYVM_UNKNOWN_OP = 2,
+    TOYVM_UNKNOWN_VM_FN = 3,
+    TOYVM_PANIC = 4,
+    TOYVM_NOT_TRANSLATED = 5,
+    TOYVM_UNCAUGHT = 6,
+    TOYVM_JUMP_OUT_OF_RANGE = 7,
+    TOYVM_NO_FRAME = 8,
+    TOYVM_BAD_LOCAL = 9,
+    TOYVM_BAD_GLOBAL = 10,
+    TOYVM_IMMUTABLE_GLOBAL = 11,
+    TOYVM_TABLE_OUT_OF_RANGE = 12,
+    TOYVM_SIGNATURE_MISMATCH = 13,
+    TOYVM_DIVISION_BY_ZERO = 14,
+    TOYVM_LOCALS_OVERFLOW = 15,
+};
+
+#define TOYVM_PAGE_SIZE 0x1000
+
+typedef struct {
+    toyvm *vm;
+    uint32_t ip;
+    jmp_buf env;
+} toyvm_ctx;
+
+static inline void toyvm_trap(toyvm_ctx *c, int code) { longjmp(c->env, code); }
+
+static inline uint8_t *toyvm_at(toyvm_ctx *c, uint64_t addr, uint64_t n) {
+    if (addr + n > c->vm->memory_len) toyvm_trap(c, TOYVM_PANIC);
+    return c->vm->memory + addr;
+}
+
+static inline int32_t toyvm_rd32(toyvm_ctx *c, uint64_t addr) {
+    int32_t v;
+    memcpy(&v, toyvm_at(c, addr, 4), 4);
+    return v;
+}
+
+static inline int16_t toyvm_rd16(toyvm_ctx *c, uint64_t addr) {
+    int16_t v;
+    memcpy(&v, toyvm_at(c, addr, 2), 2);
+    return v;
+}
+
+static inline uint8_t toyvm_rd8(toyvm_ctx *c, uint64_t addr) { return *toyvm_at(c, addr, 1); }
+
+static inline void toyvm_wr32(toyvm_ctx *c, uint64_t addr, int32_t v) {
+    memcpy(toyvm_at(c, addr, 4), &v, 4);
+}
+
+static inline void toyvm_wr16(toyvm_ctx *c, uint64_t addr, int32_t v) {
+    int16_t w = (int16_t)v;
+    memcpy(toyvm_at(c, addr, 2), &w, 2);
+}
+
+static inline void toyvm_wr8(toyvm_ctx *c, uint64_t addr, int32_t v) {
+    *toyvm_at(c, addr, 1) = (uint8_t)v;
+}
+
+static inline void toyvm_push_to(toyvm_ctx *c, uint32_t cell, int32_t v) {
+    uint32_t sp = (uint32_t)toyvm_rd32(c, cell);
+    toyvm_wr32(c, sp, v);
+    toyvm_wr32(c, cell, (int32_t)(sp - 4));
+}
+
+static inline int32_t toyvm_pop_from(toyvm_ctx *c, uint32_t cell) {
+    uint32_t sp = (uint32_t)toyvm_rd32(c, cell);
+    int32_t v = toyvm_rd32(c, (uint64_t)sp + 4);
+    toyvm_wr32(c, cell, (int32_t)(sp + 4));
+    return v;
+}
+
+#define PUSH(v) toyvm_push_to(c, c->vm->pstack_top, (int32_t)(v))
+#define POP() toyvm_pop_from(c, c->vm->pstack_top)
+#define RS_PUSH(v) toyvm_push_to(c, c->vm->rstack_top, (int32_t)(v))
+#define RS_POP() toyvm_pop_from(c, c->vm->rstack_top)
+#define U(v) ((uint32_t)(v))
+#define TRAP(code) toyvm_trap(c, code)
+
+static void toyvm_catch(toyvm_ctx *c, uint32_t handler) {
+    toyvm *vm = c->vm;
+    if (vm->catch_depth == TOYVM_MAX_CATCH) TRAP(TOYVM_PANIC);
+    toyvm_catch_frame *f = &vm->catch_frames[vm->catch_depth++];
+    f->addr = handler;
+    f->psp = U(toyvm_rd32(c, vm->pstack_top));
+    f->rsp = U(toyvm_rd32(c, vm->rstack_top));
+    f->frames_len = vm->frames_len;
+    f->locals_len = vm->locals_len;
+}
+
+/* unwinds to the innermost handler and continues there with `code` pushed */
+static void toyvm_throw(toyvm_ctx *c, int32_t code) {
+    toyvm *vm = c->vm;
+    if (vm->catch_depth == 0) {
+        vm->trap_arg = U(code);
+        TRAP(TOYVM_UNCAUGHT);
+    }
+    toyvm_catch_frame *f = &vm->catch_frames[--vm->catch_depth];
+    toyvm_wr32(c, vm->pstack_top, (int32_t)f->psp);
+    toyvm_wr32(c, vm->rstack_top, (int32_t)f->rsp);
+    vm->frames_len = f->frames_len;
+    vm->locals_len = f->locals_len;
+    PUSH(code);
+    c->ip = f->addr;
+}
+
+/* drops the handlers installed by the function being left, the rstack grows down */
+static void toyvm_leave_catch(toyvm_ctx *c) {
+    toyvm *vm = c->vm;
+    uint32_t rsp = U(toyvm_rd32(c, vm->rstack_top));
+    while (vm->catch_depth && vm->catch_frames[vm->catch_depth - 1].rsp <= rsp) {
+        vm->catch_depth--;
+    }
+}
+
+/* sets up a frame of `n` local slots, initially 0 */
+static void toyvm_enter(toyvm_ctx *c, uint32_t n) {
+    toyvm *vm = c->vm;
+    if (vm->frames_len == TOYVM_MAX_FRAMES) TRAP(TOYVM_PANIC);
+    if (n > TOYVM_MAX_LOCALS - vm->locals_len) TRAP(TOYVM_LOCALS_OVERFLOW);
+    vm->frames[vm->frames_len++] = vm->locals_len;
+    memset(vm->locals + vm->locals_len, 0, n * sizeof(int32_t));
+    vm->locals_len += n;
+}
+
+static void toyvm_leave(toyvm_ctx *c) {
+    toyvm *vm = c->vm;
+    if (vm->frames_len == 0) TRAP(TOYVM_NO_FRAME);
+    vm->locals_len = vm->frames[--vm->frames_len];
+}
+
+/* slot `index` of the innermost frame */
+static int32_t *toyvm_local(toyvm_ctx *c, uint32_t index) {
+    toyvm *vm = c->vm;
+    size_t base = vm->frames_len ? vm->frames[vm->frames_len - 1] : vm->locals_len;
+    if (vm->frames_len == 0 || index >= vm->locals_len - base) {
+        vm->trap_arg = index;
+        TRAP(TOYVM_BAD_LOCAL);
+    }
+    return &vm->locals[base + index];
+}
+
+/* global `index` for an access by guest code */
+static toyvm_global *toyvm_global_at(toyvm_ctx *c, uint32_t index, uint8_t is_i64) {
+    toyvm *vm = c->vm;
+    if (index >= vm->globals_len || vm->globals[index].is_i64 != is_i64) {
+        vm->trap_arg = index;
+        TRAP(TOYVM_BAD_GLOBAL);
+    }
+    return &vm->globals[index];
+}
+
+static void toyvm_global_set(toyvm_ctx *c, uint32_t index, uint8_t is_i64, int64_t value) {
+    toyvm_global *g = toyvm_global_at(c, index, is_i64);
+    if (!g->is_mutable) {
+        c->vm->trap_arg = index;
+        TRAP(TOYVM_IMMUTABLE_GLOBAL);
+    }
+    g->value = value;
+}
+
+/* entry of function `index` for a CALL_INDIRECT returning to `ret` */
+static uint32_t toyvm_call_indirect(toyvm_ctx *c, uint32_t index, uint32_t signature,
+                                    uint32_t ret) {
+    toyvm *vm = c->vm;
+    if (index >= vm->table_len) {
+        vm->trap_arg = index;
+        TRAP(TOYVM_TABLE_OUT_OF_RANGE);
+    }
+    if (vm->table[index].signature != signature) {
+        vm->trap_arg = index;
+        TRAP(TOYVM_SIGNATURE_MISMATCH);
+    }
+    RS_PUSH(ret);
+    return vm->table[index].entry;
+}
+
+/* target of a relative jump, `base` being the address after its opcode */
+static inline uint32_t toyvm_relative(toyvm_ctx *c, uint32_t base, int32_t offset) {
+    int64_t target = (int64_t)base + offset;
+    if (target < 0 || (uint64_t)target >= c->vm->memory_len) TRAP(TOYVM_JUMP_OUT_OF_RANGE);
+    return (uint32_t)target;
+}
+
+static inline int32_t toyvm_rotl32(int32_t a, int32_t b) {
+    uint32_t n = U(b) & 31;
+    return (int32_t)(n ? (U(a) << n) | (U(a) >> (32 - n)) : U(a));
+}
+
+static inline int32_t toyvm_rotr32(int32_t a, int32_t b) {
+    uint32_t n = U(b) & 31;
+    return (int32_t)(n ? (U(a) >> n) | (U(a) << (32 - n)) : U(a));
+}
+
+static inline int32_t toyvm_sat32(int64_t v) {
+    return v < INT32_MIN ? INT32_MIN : v > INT32_MAX ? INT32_MAX : (int32_t)v;
+}
+
+static inline uint32_t toyvm_satu32(uint64_t v) {
+    return v > UINT32_MAX ? UINT32_MAX : (uint32_t)v;
+}
+
+static inline uint32_t toyvm_clz(uint64_t v, uint32_t bits) {
+    uint32_t n = bits;
+    for (; v; v >>= 1) n--;
+    return n;
+}
+
+static inline uint32_t toyvm_ctz(uint64_t v, uint32_t bits) {
+    uint32_t n = 0;
+    while (n < bits && !(v >> n & 1)) n++;
+    return n;
+}
+
+static inline uint32_t toyvm_popcnt(uint64_t v) {
+    uint32_t n = 0;
+    for (; v; v &= v - 1) n++;
+    return n;
+}
+
+static inline uint64_t toyvm_bswap(uint64_t v, uint32_t bytes) {
+    uint64_t r = 0;
+    for (uint32_t i = 0; i < bytes; i++) r = r << 8 | (v >> 8 * i & 0xff);
+    return r;
+}
+
+/* mask of a bitfield, `offset` is reduced to 0..31 in place */
+static inline uint32_t toyvm_bitfield(uint32_t *offset, uint32_t width) {
+    *offset &= 31;
+    if (width > 32 - *offset) width = 32 - *offset;
+    return width == 32 ? 0xffffffffu : (1u << width) - 1;
+}
+
+static inline int32_t toyvm_memory_grow(toyvm *vm, int32_t delta) {
+    size_t pages = vm->memory_len / TOYVM_PAGE_SIZE;
+    if (delta < 0 || pages + (size_t)delta > vm->max_pages) return -1;
+    size_t len = vm->memory_len + (size_t)delta * TOYVM_PAGE_SIZE;
+    uint8_t *memory = realloc(vm->memory, len);
+    if (!memory) return -1;
+    memset(memory + vm->memory_len, 0, len - vm->memory_len);
+    vm->memory = memory;
+    vm->memory_len = len;
+    return (int32_t)pages;
+}
+
+void toyvm_push_i32(toyvm *vm, int32_t v) {
+    uint32_t sp;
+    memcpy(&sp, vm->memory + vm->pstack_top, 4);
+    memcpy(vm->memory + sp, &v, 4);
+    sp -= 4;
+    memcpy(vm->memory + vm->pstack_top, &sp, 4);
+}
+
+int32_t toyvm_pop_i32(toyvm *vm) {
+    uint32_t sp;
+    int32_t v;
+    memcpy(&sp, vm->memory + vm->pstack_top, 4);
+    sp += 4;
+    memcpy(&v, vm->memory + sp, 4);
+    memcpy(vm->memory + vm->pstack_top, &sp, 4);
+    return v;
+}
+
+static void toyvm_exec(toyvm_ctx *c) {
+    int32_t a, b;
+    for (;;) {
+        switch (c->ip) {
+"#;
+
+const EPILOGUE: &str = r#"        default:
+            toyvm_trap(c, TOYVM_NOT_TRANSLATED);
+        }
+    }
+}
+
+#undef PUSH
+#undef POP
+#undef RS_PUSH
+#undef RS_POP
+#undef U
+#undef TRAP
+
+/* runs from `ip` until END, like VM::run */
+int toyvm_run(toyvm *vm, uint32_t ip) {
+    toyvm_ctx c;
+    c.vm = vm;
+    c.ip = ip;
+    int code = setjmp(c.env);
+    if (code == 0) {
+        toyvm_exec(&c);
+    }
+    /* the CATCH handlers and frames go with the run installing them, as in VM::run */
+    vm->catch_depth = 0;
+    vm->frames_len = 0;
+    vm->locals_len = 0;
+    vm->ip = c.ip;
+    return code;
+}
+"#;
+
+/// translates `image`, loaded at address `base`, into C.
+/// for every `(name, addr)` in `entries` a function `int name(toyvm *vm)` is
+/// generated that runs from `addr` until `END`. names have to be valid C identifiers
+pub fn translate(image: &[u8], base: usize, entries: &[(&str, usize)]) -> String {
+    // decode against a buffer laid out like memory so addresses match
+    let mut memory = vec![0; base];
+    memory.extend_from_slice(image);
+
+    let mut out = String::from(PRELUDE);
+    let mut addr = base;
+    while let Some(instr) = decode(&memory, addr) {
+        let next = instr.next();
+        let target = instr.target().unwrap_or(0);
+        let imm = instr.imm_i32();
+        let after_op = addr + 1;
+        if addr != base {
+            writeln!(out, "            /* fallthrough */").unwrap();
+        }
+        writeln!(out, "        case {addr}: /* {instr} */").unwrap();
+        writeln!(out, "            c->ip = {next};").unwrap();
+        let body = match instr.op {
+            opcode::UNREACHABLE => format!("c->ip = {after_op}; TRAP(TOYVM_UNREACHABLE);"),
+            opcode::NOP => String::new(),
+            opcode::END => "return;".to_string(),
+            // relative jumps leaving the image are checked against the size of memory
+            opcode::JMPI if target >= memory.len() => {
+                format!(
+                    "c->ip = {after_op}; c->ip = toyvm_relative(c, {after_op}, {imm}); continue;"
+                )
+            }
+            opcode::JZI if target >= memory.len() => format!(
+                "c->ip = {after_op}; \
+                if (POP() == 0) {{ c->ip = toyvm_relative(c, {after_op}, {imm}); continue; }} \
+                c->ip = {next};"
+            ),
+            opcode::BRI | opcode::JMPI => format!("c->ip = {target}; continue;"),
+            opcode::BRZI | opcode::JZI => {
+                format!(
+                    "c->ip = {after_op}; \
+                    if (POP() == 0) {{ c->ip = {target}; continue; }} \
+                    c->ip = {next};"
+                )
+            }
+            opcode::BR => "c->ip = U(POP()); continue;".to_string(),
+            opcode::BRZ => {
+                "a = POP(); b = POP(); if (a == 0) { c->ip = U(b); continue; }".to_string()
+            }
+            opcode::JMP => format!("c->ip = toyvm_relative(c, {after_op}, POP()); continue;"),
+            opcode::JZ => format!(
+                "a = POP(); b = POP(); \
+                if (a == 0) {{ c->ip = toyvm_relative(c, {after_op}, b); continue; }}"
+            ),
+            opcode::RETURN => "toyvm_leave_catch(c); c->ip = U(RS_POP()); continue;".to_string(),
+            opcode::CALL_VM => "a = POP(); \
+                if (U(a) >= c->vm->functions_len) { \
+                c->vm->trap_arg = U(a); TRAP(TOYVM_UNKNOWN_VM_FN); } \
+                c->vm->functions[a](c->vm);"
+                .to_string(),
+            opcode::CALL => format!("RS_PUSH({after_op}); c->ip = U(POP()); continue;"),
+            opcode::CALLI => format!("RS_PUSH({next}); c->ip = {target}; continue;"),
+            opcode::CALL_INDIRECT => format!(
+                "c->ip = {after_op}; \
+                c->ip = toyvm_call_indirect(c, U(POP()), U({imm}), {next}); continue;"
+            ),
+            opcode::TAIL_CALL => "toyvm_leave_catch(c); c->ip = U(POP()); continue;".to_string(),
+            opcode::TAIL_CALLI => format!("toyvm_leave_catch(c); c->ip = {target}; continue;"),
+            opcode::BR_TABLE => {
+                let targets = table(&memory, &instr);
+                let (default, targets) = targets.split_last().unwrap();
+                let mut cases = String::new();
+                for (i, target) in targets.iter().enumerate() {
+                    write!(cases, "case {i}: c->ip = {target}; continue; ").unwrap();
+                }
+                format!("switch (U(POP())) {{ {cases}default: c->ip = {default}; continue; }}")
+            }
+            opcode::CATCH => format!("toyvm_catch(c, {target});"),
+            opcode::END_CATCH => "if (c->vm->catch_depth) c->vm->catch_depth--;".to_string(),
+            opcode::THROW => "a = POP(); if (a != 0) { toyvm_throw(c, a); continue; }".to_string(),
+            opcode::ENTER => {
+                format!("c->ip = {after_op}; toyvm_enter(c, U({imm})); c->ip = {next};")
+            }
+            opcode::LEAVE => "toyvm_leave(c);".to_string(),
+            opcode::LOCAL_GET => format!(
+                "c->ip = {after_op}; a = *toyvm_local(c, U({imm})); PUSH(a); c->ip = {next};"
+            ),
+            opcode::LOCAL_SET => format!(
+                "c->ip = {after_op}; a = POP(); *toyvm_local(c, U({imm})) = a; c->ip = {next};"
+            ),
+            opcode::LOCAL_TEE => format!(
+                "c->ip = {after_op}; a = POP(); *toyvm_local(c, U({imm})) = a; PUSH(a); \
+                c->ip = {next};"
+            ),
+            opcode::GLOBAL_GET => format!(
+                "c->ip = {after_op}; a = (int32_t)toyvm_global_at(c, U({imm}), 0)->value; PUSH(a); \
+                c->ip = {next};"
+            ),
+            opcode::GLOBAL_SET => format!(
+                "c->ip = {after_op}; a = POP(); toyvm_global_set(c, U({imm}), 0, a); c->ip = {next};"
+            ),
+            opcode::I64_GLOBAL_GET => format!(
+                "c->ip = {after_op}; \
+                {{ int64_t v = toyvm_global_at(c, U({imm}), 1)->value; PUSH(v >> 32); PUSH(v); }} \
+                c->ip = {next};"
+            ),
+            opcode::I64_GLOBAL_SET => format!(
+                "c->ip = {after_op}; a = POP(); b = POP(); \
+                toyvm_global_set(c, U({imm}), 1, (int64_t)(((uint64_t)U(b) << 32) | U(a))); \
+                c->ip = {next};"
+            ),
+            opcode::ADD_OVF_S | opcode::SUB_OVF_S | opcode::MUL_OVF_S => {
+                let op = match instr.op {
+                    opcode::ADD_OVF_S => '+',
+                    opcode::SUB_OVF_S => '-',
+                    _ => '*',
+                };
+                format!(
+                    "b = POP(); a = POP(); \
+                    {{ int64_t r = (int64_t)a {op} b; PUSH(r); PUSH(r != (int32_t)r); }}"
+                )
+            }
+            // wrapping below 0 ends up above UINT32_MAX, too
+            opcode::ADD_OVF_U | opcode::SUB_OVF_U | opcode::MUL_OVF_U => {
+                let op = match instr.op {
+                    opcode::ADD_OVF_U => '+',
+                    opcode::SUB_OVF_U => '-',
+                    _ => '*',
+                };
+                format!(
+                    "b = POP(); a = POP(); \
+                    {{ uint64_t r = (uint64_t)U(a) {op} U(b); PUSH(r); PUSH(r > UINT32_MAX); }}"
+                )
+            }
+            opcode::DIVMOD_S => "b = POP(); a = POP(); if (b == 0) TRAP(TOYVM_DIVISION_BY_ZERO); \
+                if (b == -1) { PUSH(0); PUSH(-U(a)); } else { PUSH(a % b); PUSH(a / b); }"
+                .to_string(),
+            opcode::DIVMOD_U => "b = POP(); a = POP(); if (b == 0) TRAP(TOYVM_DIVISION_BY_ZERO); \
+                PUSH(U(a) % U(b)); PUSH(U(a) / U(b));"
+                .to_string(),
+            opcode::DROP => "POP();".to_string(),
+            opcode::DUP => "a = POP(); PUSH(a); PUSH(a);".to_string(),
+            opcode::SWAP => "a = POP(); b = POP(); PUSH(a); PUSH(b);".to_string(),
+            opcode::MEMORY_SIZE => "PUSH(c->vm->memory_len / TOYVM_PAGE_SIZE);".to_string(),
+            opcode::MEMORY_GROW => "a = POP(); PUSH(toyvm_memory_grow(c->vm, a));".to_string(),
+            opcode::I32_LOAD => "a = POP(); PUSH(toyvm_rd32(c, U(a)));".to_string(),
+            opcode::I32_LOAD_8 => "a = POP(); PUSH(toyvm_rd8(c, U(a)));".to_string(),
+            opcode::I32_LOAD_16 => "a = POP(); PUSH(toyvm_rd16(c, U(a)));".to_string(),
+            opcode::I32_LOAD_8_S => "a = POP(); PUSH((int8_t)toyvm_rd8(c, U(a)));".to_string(),
+            opcode::I32_LOAD_16_U => "a = POP(); PUSH((uint16_t)toyvm_rd16(c, U(a)));".to_string(),
+            opcode::I64_LOAD_8
+            | opcode::I64_LOAD_8_S
+            | opcode::I64_LOAD_16
+            | opcode::I64_LOAD_16_S
+            | opcode::I64_LOAD_32
+            | opcode::I64_LOAD_32_S => {
+                let load = match instr.op {
+                    opcode::I64_LOAD_8 => "toyvm_rd8(c, U(a))",
+                    opcode::I64_LOAD_8_S => "(int8_t)toyvm_rd8(c, U(a))",
+                    opcode::I64_LOAD_16 => "(uint16_t)toyvm_rd16(c, U(a))",
+                    opcode::I64_LOAD_16_S => "toyvm_rd16(c, U(a))",
+                    opcode::I64_LOAD_32 => "U(toyvm_rd32(c, U(a)))",
+                    _ => "toyvm_rd32(c, U(a))",
+                };
+                format!("a = POP(); {{ int64_t v = {load}; PUSH(v >> 32); PUSH(v); }}")
+            }
+            opcode::I32_STORE => "a = POP(); b = POP(); toyvm_wr32(c, U(a), b);".to_string(),
+            opcode::I32_STORE_8 => "a = POP(); b = POP(); toyvm_wr8(c, U(a), b);".to_string(),
+            opcode::I32_STORE_16 => "a = POP(); b = POP(); toyvm_wr16(c, U(a), b);".to_string(),
+            opcode::I32_CONST => format!("PUSH({imm});"),
+            opcode::EQZ => "a = POP(); PUSH(a == 0);".to_string(),
+            opcode::NOT => "a = POP(); PUSH(~a);".to_string(),
+            opcode::INC => "a = POP(); PUSH(U(a) + 1);".to_string(),
+            opcode::DEC => "a = POP(); PUSH(U(a) - 1);".to_string(),
+            opcode::ZERO => "PUSH(0);".to_string(),
+            opcode::EXTEND8_S => "a = POP(); PUSH((int8_t)a);".to_string(),
+            opcode::EXTEND16_S => "a = POP(); PUSH((int16_t)a);".to_string(),
+            opcode::CLZ => "a = POP(); PUSH(toyvm_clz(U(a), 32));".to_string(),
+            opcode::CTZ => "a = POP(); PUSH(toyvm_ctz(U(a), 32));".to_string(),
+            opcode::POPCNT => "a = POP(); PUSH(toyvm_popcnt(U(a)));".to_string(),
+            opcode::BSWAP => "a = POP(); PUSH(toyvm_bswap(U(a), 4));".to_string(),
+            opcode::I64_CLZ | opcode::I64_CTZ | opcode::I64_POPCNT | opcode::I64_BSWAP => {
+                let expr = match instr.op {
+                    opcode::I64_CLZ => "toyvm_clz(v, 64)",
+                    opcode::I64_CTZ => "toyvm_ctz(v, 64)",
+                    opcode::I64_POPCNT => "toyvm_popcnt(v)",
+                    _ => "toyvm_bswap(v, 8)",
+                };
+                format!(
+                    "a = POP(); b = POP(); \
+                    {{ uint64_t v = (uint64_t)U(b) << 32 | U(a); v = {expr}; PUSH(v >> 32); PUSH(v); }}"
+                )
+            }
+            opcode::BITFIELD_EXTRACT => "b = POP(); a = POP(); \
+                { uint32_t offset = U(a); uint32_t mask = toyvm_bitfield(&offset, U(b)); \
+                a = POP(); PUSH(U(a) >> offset & mask); }"
+                .to_string(),
+            opcode::BITFIELD_INSERT => "b = POP(); a = POP(); \
+                { uint32_t offset = U(a); uint32_t mask = toyvm_bitfield(&offset, U(b)); \
+                uint32_t field = U(POP()); a = POP(); \
+                PUSH((U(a) & ~(mask << offset)) | (field & mask) << offset); }"
+                .to_string(),
+            // the interpreter panics on these
+            opcode::SELECT | opcode::I64_CONST => {
+                format!("c->ip = {after_op}; TRAP(TOYVM_PANIC);")
+            }
+            op => match binary(op) {
+                Some(expr) => format!("b = POP(); a = POP(); {expr}"),
+                None => {
+                    format!("c->ip = {after_op}; c->vm->trap_arg = {op}; TRAP(TOYVM_UNKNOWN_OP);")
+                }
+            },
+        };
+        if !body.is_empty() {
+            writeln!(out, "            {body}").unwrap();
+        }
+        addr = next;
+    }
+    writeln!(out, "            /* fallthrough */").unwrap();
+    out.push_str(EPILOGUE);
+
+    for (name, addr) in entries {
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "int {name}(toyvm *vm) {{ return toyvm_run(vm, {addr}); }}"
+        )
+        .unwrap();
+    }
+    out
+}
+
+// ( a b -- r ), pushing the result
+fn binary(op: u8) -> Option<&'static str> {
+    Some(match op {
+        opcode::EQ => "PUSH(a == b);",
+        opcode::NE => "PUSH(a != b);",
+        opcode::LT_S => "PUSH(a < b);",
+        opcode::LT_U => "PUSH(U(a) < U(b));",
+        opcode::GT_S => "PUSH(a > b);",
+        opcode::GT_U => "PUSH(U(a) > U(b));",
+        opcode::LE_S => "PUSH(a <= b);",
+        opcode::LE_U => "PUSH(U(a) <= U(b));",
+        opcode::GE_S => "PUSH(a >= b);",
+        opcode::GE_U => "PUSH(U(a) >= U(b));",
+        opcode::ADD => "PUSH(U(a) + U(b));",
+        opcode::SUB => "PUSH(U(a) - U(b));",
+        opcode::MUL => "PUSH(U(a) * U(b));",
+        opcode::DIV_S => {
+            "if (b == 0 || (a == INT32_MIN && b == -1)) TRAP(TOYVM_PANIC); PUSH(a / b);"
+        }
+        opcode::DIV_U => "if (b == 0) TRAP(TOYVM_PANIC); PUSH(U(a) / U(b));",
+        opcode::MOD_S => {
+            "if (b == 0 || (a == INT32_MIN && b == -1)) TRAP(TOYVM_PANIC); PUSH(a % b);"
+        }
+        opcode::MOD_U => "if (b == 0) TRAP(TOYVM_PANIC); PUSH(U(a) % U(b));",
+        opcode::ADD_SAT_S => "PUSH(toyvm_sat32((int64_t)a + b));",
+        opcode::ADD_SAT_U => "PUSH(toyvm_satu32((uint64_t)U(a) + U(b)));",
+        opcode::SUB_SAT_S => "PUSH(toyvm_sat32((int64_t)a - b));",
+        opcode::SUB_SAT_U => "PUSH(U(a) < U(b) ? 0 : U(a) - U(b));",
+        opcode::MUL_SAT_S => "PUSH(toyvm_sat32((int64_t)a * b));",
+        opcode::MUL_SAT_U => "PUSH(toyvm_satu32((uint64_t)U(a) * U(b)));",
+        opcode::MUL_HI_S => "PUSH((int64_t)a * b >> 32);",
+        opcode::MUL_HI_U => "PUSH((uint64_t)U(a) * U(b) >> 32);",
+        opcode::AND => "PUSH(a & b);",
+        opcode::OR => "PUSH(a | b);",
+        opcode::XOR => "PUSH(a ^ b);",
+        opcode::SHL => "PUSH(U(a) << (U(b) & 31));",
+        opcode::SHR_S => "PUSH(a >> (U(b) & 31));",
+        opcode::SHR_U => "PUSH(U(a) >> (U(b) & 31));",
+        opcode::ROTL => "PUSH(toyvm_rotl32(a, b));",
+        opcode::ROTR => "PUSH(toyvm_rotr32(a, b));",
+        opcode::MIN => "PUSH(a < b ? a : b);",
+        opcode::MAX => "PUSH(a > b ? a : b);",
+        _ => return None,
+    })
+}
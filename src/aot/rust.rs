@@ -0,0 +1,272 @@
+//! translation of a code image into a Rust module, meant to be run from a build script:
+//!
+//! ```ignore
+//! // build.rs
+//! let image = std::fs::read("script.bin").unwrap();
+//! let code = toyvm::aot::rust::translate(&image, 0x10, &[("main", 0x10)]);
+//! let out = std::env::var("OUT_DIR").unwrap();
+//! std::fs::write(format!("{out}/script.rs"), code).unwrap();
+//!
+//! // lib.rs
+//! mod script {
+//!     include!(concat!(env!("OUT_DIR"), "/script.rs"));
+//! }
+//! script::main(&mut vm)?;
+//! ```
+//!
+//! every guest function becomes a Rust function working on the `VM` it is given,
+//! with stacks, memory, regions and host functions behaving exactly as when
+//! interpreted: every instruction is checked against the regions before it runs.
+//! the generated code is made of the primitives in `aot::rt`.
+//! control reaching code outside the image falls back to the interpreter
+
+use crate::{
+    analysis::is_control,
+    decode::{Instruction, decode, table},
+    opcode,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+struct Function {
+    entry: usize,
+    // leader -> instructions of the basic block
+    blocks: BTreeMap<usize, Vec<Instruction>>,
+}
+
+/// translates `image`, loaded at address `base`, into Rust source.
+/// for every `(name, addr)` in `entries` a function `pub fn name(vm: &mut VM) -> Result<()>`
+/// is generated that runs from `addr` until `END`, like `VM::run`
+pub fn translate(image: &[u8], base: usize, entries: &[(&str, usize)]) -> String {
+    // decode against a buffer laid out like memory so addresses match
+    let mut memory = vec![0; base];
+    memory.extend_from_slice(image);
+
+    let mut functions: Vec<Function> = Vec::new();
+    let mut work: Vec<usize> = entries.iter().map(|(_, addr)| *addr).collect();
+    work.reverse();
+    while let Some(entry) = work.pop() {
+        if functions.iter().any(|f| f.entry == entry) {
+            continue;
+        }
+        let f = function(&memory, base, entry, &mut work);
+        functions.push(f);
+    }
+
+    let mut out = String::new();
+    writeln!(out, "// generated by toyvm::aot::rust, do not edit").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "use toyvm::{{Result, VM, aot::rt}};").unwrap();
+
+    for (name, addr) in entries {
+        writeln!(out).unwrap();
+        writeln!(out, "pub fn {name}(vm: &mut VM) -> Result<()> {{").unwrap();
+        writeln!(out, "    rt::run(vm, &mut {addr}, dispatch)").unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "fn dispatch(vm: &mut VM, ip: &mut usize) -> Result<bool> {{"
+    )
+    .unwrap();
+    writeln!(out, "    match *ip {{").unwrap();
+    let mut seen = BTreeSet::new();
+    for f in &functions {
+        let leaders: Vec<String> = f
+            .blocks
+            .keys()
+            .filter(|leader| seen.insert(**leader))
+            .map(|leader| leader.to_string())
+            .collect();
+        if !leaders.is_empty() {
+            writeln!(
+                out,
+                "        {} => guest_{:x}(vm, ip),",
+                leaders.join(" | "),
+                f.entry
+            )
+            .unwrap();
+        }
+    }
+    writeln!(out, "        _ => rt::exec(vm, ip),").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    for f in &functions {
+        writeln!(out).unwrap();
+        writeln!(out, "// guest function at {:#x}", f.entry).unwrap();
+        // blocks that all leave the function still get the loop
+        writeln!(out, "#[allow(clippy::never_loop)]").unwrap();
+        writeln!(
+            out,
+            "fn guest_{:x}(vm: &mut VM, ip: &mut usize) -> Result<bool> {{",
+            f.entry
+        )
+        .unwrap();
+        writeln!(out, "    loop {{").unwrap();
+        writeln!(out, "        match *ip {{").unwrap();
+        for (leader, block) in &f.blocks {
+            writeln!(out, "            {leader} => {{").unwrap();
+            for instr in block {
+                writeln!(out, "                // {instr}").unwrap();
+                writeln!(out, "                rt::at(vm, {})?;", instr.addr).unwrap();
+                for line in statements(&memory, instr).lines() {
+                    writeln!(out, "                {line}").unwrap();
+                }
+            }
+            writeln!(out, "            }}").unwrap();
+        }
+        writeln!(out, "            _ => return Ok(true),").unwrap();
+        writeln!(out, "        }}").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+    out
+}
+
+// basic blocks reachable from `entry` without following calls.
+// call targets are added to `functions`
+fn function(memory: &[u8], base: usize, entry: usize, functions: &mut Vec<usize>) -> Function {
+    let decode_at = |addr: usize| decode(memory, addr).filter(|_| addr >= base);
+
+    let mut reachable = BTreeMap::new();
+    let mut leaders = BTreeSet::from([entry]);
+    let mut work = vec![entry];
+    while let Some(addr) = work.pop() {
+        if reachable.contains_key(&addr) {
+            continue;
+        }
+        let Some(instr) = decode_at(addr) else {
+            continue;
+        };
+        reachable.insert(addr, instr);
+        let next = instr.next();
+        match instr.op {
+            opcode::BRI | opcode::JMPI => {
+                leaders.insert(instr.target().unwrap());
+                work.push(instr.target().unwrap());
+            }
+            opcode::BRZI | opcode::JZI | opcode::CATCH => {
+                leaders.extend([instr.target().unwrap(), next]);
+                work.extend([instr.target().unwrap(), next]);
+            }
+            opcode::CALLI => {
+                functions.push(instr.target().unwrap());
+                leaders.insert(next);
+                work.push(next);
+            }
+            opcode::TAIL_CALLI => functions.push(instr.target().unwrap()),
+            opcode::BR_TABLE => {
+                let targets = table(memory, &instr);
+                leaders.extend(&targets);
+                work.extend(targets);
+            }
+            opcode::CALL | opcode::CALL_INDIRECT | opcode::BRZ | opcode::JZ | opcode::THROW => {
+                leaders.insert(next);
+                work.push(next);
+            }
+            opcode::UNREACHABLE
+            | opcode::END
+            | opcode::BR
+            | opcode::JMP
+            | opcode::RETURN
+            | opcode::TAIL_CALL => {}
+            _ => work.push(next),
+        }
+    }
+
+    let mut blocks = BTreeMap::new();
+    for &leader in &leaders {
+        let mut block = Vec::new();
+        let mut addr = leader;
+        while let Some(instr) = reachable.get(&addr) {
+            block.push(*instr);
+            addr = instr.next();
+            if is_control(instr.op) || leaders.contains(&addr) {
+                break;
+            }
+        }
+        if !block.is_empty() {
+            blocks.insert(leader, block);
+        }
+    }
+    Function { entry, blocks }
+}
+
+// `ip` is kept exactly as `VM::step` leaves it, errors included,
+// and the immediate is checked where the interpreter fetches it
+fn statements(memory: &[u8], instr: &Instruction) -> String {
+    let next = instr.next();
+    let after_op = instr.addr + 1;
+    let target = instr.target().unwrap_or(0);
+    let fetch = format!("rt::fetch(vm, {after_op})?;");
+    let simple = |code: &str| format!("*ip = {next};\n{code}");
+    let binary = |expr: &str| {
+        simple(&format!(
+            "let b = rt::pop(vm)?;\nlet a = rt::pop(vm)?;\nrt::push(vm, {expr})?;"
+        ))
+    };
+    let execute = || {
+        let op = instr.op;
+        format!("*ip = {after_op};\nif !rt::execute(vm, {op}, ip)? {{\n    return Ok(false);\n}}")
+    };
+    match instr.op {
+        // the vm checks relative jumps leaving the image against the size of memory
+        opcode::JMPI | opcode::JZI if target >= memory.len() => execute(),
+        opcode::NOP => format!("*ip = {next};"),
+        opcode::END => format!("*ip = {after_op};\nreturn Ok(false);"),
+        opcode::BRI | opcode::JMPI => format!("*ip = {after_op};\n{fetch}\n*ip = {target};"),
+        opcode::TAIL_CALLI => {
+            format!("*ip = {after_op};\n{fetch}\nrt::leave(vm);\n*ip = {target};")
+        }
+        opcode::BRZI | opcode::JZI => format!(
+            "*ip = {after_op};\nlet is_zero = rt::pop(vm)? == 0;\n{fetch}\n*ip = if is_zero {{ {target} }} else {{ {next} }};"
+        ),
+        opcode::CALLI => {
+            format!("*ip = {after_op};\nrt::rs_push(vm, {next})?;\n{fetch}\n*ip = {target};")
+        }
+        opcode::RETURN => {
+            format!("*ip = {after_op};\nrt::leave(vm);\n*ip = rt::rs_pop(vm)? as usize;")
+        }
+        opcode::DROP => simple("rt::pop(vm)?;"),
+        opcode::DUP => simple("let a = rt::pop(vm)?;\nrt::push(vm, a)?;\nrt::push(vm, a)?;"),
+        opcode::SWAP => simple(
+            "let a = rt::pop(vm)?;\nlet b = rt::pop(vm)?;\nrt::push(vm, a)?;\nrt::push(vm, b)?;",
+        ),
+        opcode::I32_CONST => format!(
+            "*ip = {after_op};\n{fetch}\n*ip = {next};\nrt::push(vm, {})?;",
+            instr.imm_i32()
+        ),
+        opcode::ZERO => simple("rt::push(vm, 0)?;"),
+        opcode::NOT => simple("let a = rt::pop(vm)?;\nrt::push(vm, !a)?;"),
+        opcode::INC => simple("let a = rt::pop(vm)?;\nrt::push(vm, a.wrapping_add(1))?;"),
+        opcode::DEC => simple("let a = rt::pop(vm)?;\nrt::push(vm, a.wrapping_sub(1))?;"),
+        opcode::EQZ => simple("let a = rt::pop(vm)?;\nrt::push(vm, i32::from(a == 0))?;"),
+        opcode::EQ => binary("i32::from(a == b)"),
+        opcode::NE => binary("i32::from(a != b)"),
+        opcode::LT_S => binary("i32::from(a < b)"),
+        opcode::LT_U => binary("i32::from((a as u32) < (b as u32))"),
+        opcode::GT_S => binary("i32::from(a > b)"),
+        opcode::GT_U => binary("i32::from((a as u32) > (b as u32))"),
+        opcode::LE_S => binary("i32::from(a <= b)"),
+        opcode::LE_U => binary("i32::from((a as u32) <= (b as u32))"),
+        opcode::GE_S => binary("i32::from(a >= b)"),
+        opcode::GE_U => binary("i32::from((a as u32) >= (b as u32))"),
+        opcode::ADD => binary("a.wrapping_add(b)"),
+        opcode::SUB => binary("a.wrapping_sub(b)"),
+        opcode::MUL => binary("a.wrapping_mul(b)"),
+        opcode::AND => binary("a & b"),
+        opcode::OR => binary("a | b"),
+        opcode::XOR => binary("a ^ b"),
+        opcode::SHL => binary("a.wrapping_shl(b as u32)"),
+        opcode::SHR_S => binary("a.wrapping_shr(b as u32)"),
+        opcode::SHR_U => binary("(a as u32).wrapping_shr(b as u32) as i32"),
+        opcode::ROTL => binary("a.rotate_left(b as u32)"),
+        opcode::ROTR => binary("a.rotate_right(b as u32)"),
+        opcode::MIN => binary("a.min(b)"),
+        opcode::MAX => binary("a.max(b)"),
+        _ => execute(),
+    }
+}
@@ -0,0 +1,110 @@
+use crate::{opcode, read_i32};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub addr: usize,
+    pub op: u8,
+    pub imm: Option<i64>,
+}
+
+impl Instruction {
+    /// encoded size in bytes, the table of a `BR_TABLE` included
+    pub fn size(&self) -> usize {
+        // saturating, so a count past the end of memory does not decode
+        let table = if self.op == opcode::BR_TABLE {
+            (self.imm_i32() as u32 as usize)
+                .saturating_add(1)
+                .saturating_mul(4)
+        } else {
+            0
+        };
+        table.saturating_add(1 + opcode::immediate_size(self.op))
+    }
+
+    /// address of the following instruction
+    pub fn next(&self) -> usize {
+        self.addr.saturating_add(self.size())
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        opcode::opcode(self.op)
+    }
+
+    /// immediate as i32, 0 if there is none
+    pub fn imm_i32(&self) -> i32 {
+        self.imm.unwrap_or(0) as i32
+    }
+
+    /// statically known destination of a branch, jump or call, the handler of a catch
+    pub fn target(&self) -> Option<usize> {
+        match self.op {
+            opcode::BRI | opcode::BRZI | opcode::CALLI | opcode::TAIL_CALLI | opcode::CATCH => {
+                Some(self.imm_i32() as usize)
+            }
+            opcode::JMPI | opcode::JZI => {
+                // may be out of range, which is left to the vm to report
+                Some((self.addr + 1).wrapping_add_signed(self.imm_i32() as isize))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())?;
+        match (self.target(), self.imm) {
+            (Some(target), _) => write!(f, " {target:#x}"),
+            (None, Some(imm)) => write!(f, " {imm}"),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// decodes the instruction at `addr`. `None` if it does not fit into `memory`
+pub fn decode(memory: &[u8], addr: usize) -> Option<Instruction> {
+    let op = *memory.get(addr)?;
+    let imm = match opcode::immediate_size(op) {
+        0 => None,
+        n => {
+            let bytes = memory.get(addr + 1..addr + 1 + n)?;
+            if n == 4 {
+                Some(read_i32(bytes) as i64)
+            } else {
+                Some(i64::from_le_bytes(bytes.try_into().ok()?))
+            }
+        }
+    };
+    let instr = Instruction { addr, op, imm };
+    if instr.next() > memory.len() {
+        return None;
+    }
+    Some(instr)
+}
+
+/// targets of a `BR_TABLE` decoded from `memory`, the default last.
+/// empty for any other instruction
+pub fn table(memory: &[u8], instr: &Instruction) -> Vec<usize> {
+    if instr.op != opcode::BR_TABLE {
+        return Vec::new();
+    }
+    (instr.addr + 5..instr.next())
+        .step_by(4)
+        .map(|addr| read_i32(&memory[addr..]) as usize)
+        .collect()
+}
+
+/// decodes `memory[from..to]` front to back
+pub fn decode_range(memory: &[u8], from: usize, to: usize) -> Vec<Instruction> {
+    let mut result = Vec::new();
+    let mut addr = from;
+    while addr < to {
+        let Some(instr) = decode(memory, addr) else {
+            break;
+        };
+        addr = instr.next();
+        result.push(instr);
+    }
+    result
+}
@@ -1,10 +1,23 @@
 #[cfg(test)]
 mod tests;
+// lets the test programs shared with `aot-tests` name the crate
+#[cfg(test)]
+extern crate self as toyvm;
 
+pub mod analysis;
+pub mod aot;
+pub mod decode;
 pub mod opcode;
+pub mod optimize;
+mod region;
+pub mod structured;
 mod vm;
 
-pub use vm::{FALSE, Result, TRUE, UnknownOpHandler, VM, VmError, VmFn};
+pub use region::{Access, Permissions, Region};
+pub use vm::{
+    Backtrace, Context, ErrorKind, FALSE, Frame, Global, PAGE_SIZE, Result, Signature, TRUE,
+    TableEntry, Trap, UnknownOpHandler, VM, ValueType, VmError, VmFn,
+};
 
 fn read_i16(bytes: &[u8]) -> i16 {
     unsafe {
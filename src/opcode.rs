@@ -5,7 +5,25 @@ macro_rules! op_code {
 }
 op_code!(UNREACHABLE, 0x00);
 op_code!(NOP, 0x01);
-// 0x02 - 0x0A reserved
+// 0x02 reserved
+// `CATCH handler` saves the stacks for `THROW`, `END_CATCH` drops the innermost.
+// `n THROW` with n != 0 restores them and continues at the handler with n pushed
+// handlers go with the function installing them on `RETURN` and tail calls, and
+// with every handler when an error leaves `VM::run`
+op_code!(CATCH, 0x03);
+op_code!(END_CATCH, 0x04);
+op_code!(THROW, 0x05);
+// `BR_TABLE n t0 .. tn-1 default` pops an index and branches to its target, to the
+// default for an index >= n. all are i32 immediates, the targets absolute
+op_code!(BR_TABLE, 0x06);
+// `ENTER n` sets up a frame of n local slots, initially 0, `LEAVE` drops the innermost.
+// frames live outside memory, see `VM::frames`
+op_code!(ENTER, 0x07);
+op_code!(LEAVE, 0x08);
+// `CALL_INDIRECT signature` pops an index into the function table and calls its entry,
+// if the entry's signature is the encoded one, see `VM::add_table_entry` and `Signature`
+op_code!(CALL_INDIRECT, 0x09);
+// 0x0A reserved
 op_code!(END, 0x0b);
 op_code!(BRI, 0x0c);
 op_code!(BRZI, 0x0d);
@@ -16,15 +34,33 @@ op_code!(CALL_VM, 0x11);
 op_code!(CALL, 0x12);
 op_code!(CALLI, 0x13);
 
+// relative jumps: the i32 offset is signed and counts from the address after the
+// opcode, e.g. `JMPI 4` continues with the next instruction. the target must be in memory
 op_code!(JMP, 0x14);
 op_code!(JMPI, 0x15);
 op_code!(JZ, 0x16);
 op_code!(JZI, 0x17);
-// 0x17 - 0x19 reserved
+// calls reusing the caller's return address, i.e. `BR`/`BRI` into a function
+op_code!(TAIL_CALL, 0x18);
+op_code!(TAIL_CALLI, 0x19);
 op_code!(DROP, 0x1a);
 op_code!(DUP, 0x1b);
 op_code!(SWAP, 0x1c);
 op_code!(SELECT, 0x1d);
+// `GLOBAL_GET` / `GLOBAL_SET` of an i64 global, two cells on the pstack
+op_code!(I64_GLOBAL_GET, 0x1e);
+op_code!(I64_GLOBAL_SET, 0x1f);
+
+// `LOCAL_GET i` and friends access slot i, an i32 immediate, of the innermost frame
+op_code!(LOCAL_GET, 0x20);
+op_code!(LOCAL_SET, 0x21);
+op_code!(LOCAL_TEE, 0x22);
+// `GLOBAL_GET i` and `GLOBAL_SET i` access i32 global i, an i32 immediate, see `VM::add_global`
+op_code!(GLOBAL_GET, 0x23);
+op_code!(GLOBAL_SET, 0x24);
+
+op_code!(MEMORY_SIZE, 0x26);
+op_code!(MEMORY_GROW, 0x27);
 
 op_code!(I32_LOAD, 0x28);
 op_code!(I32_LOAD_8, 0x29);
@@ -40,6 +76,13 @@ op_code!(I64_STORE, 0x32);
 op_code!(I64_STORE_8, 0x33);
 op_code!(I64_STORE_16, 0x34);
 op_code!(I64_STORE_32, 0x35);
+// explicit extension of the narrow loads, `I32_LOAD_8` zero-extends and `I32_LOAD_16`
+// sign-extends, the unsuffixed i64 ones zero-extend. the other extensions are 0x77 - 0x7b
+pub const I32_LOAD_8_U: u8 = I32_LOAD_8;
+pub const I32_LOAD_16_S: u8 = I32_LOAD_16;
+pub const I64_LOAD_8_U: u8 = I64_LOAD_8;
+pub const I64_LOAD_16_U: u8 = I64_LOAD_16;
+pub const I64_LOAD_32_U: u8 = I64_LOAD_32;
 
 op_code!(I32_CONST, 0x36);
 op_code!(I64_CONST, 0x37);
@@ -70,7 +113,7 @@ op_code!(I64_GE_S, 0x4c);
 op_code!(I64_GE_U, 0x4d);
 
 // 0x4c - 50 reserved for f32 - f64
-// i32
+// i32, `ADD`, `SUB`, `MUL`, `INC` and `DEC` wrap around
 op_code!(ADD, 0x51);
 op_code!(SUB, 0x52);
 op_code!(MUL, 0x53);
@@ -78,10 +121,31 @@ op_code!(DIV_S, 0x54);
 op_code!(DIV_U, 0x55);
 op_code!(MOD_S, 0x56);
 op_code!(MOD_U, 0x57);
+// saturating at the i32 / u32 bounds
+op_code!(ADD_SAT_S, 0x7e);
+op_code!(ADD_SAT_U, 0x7f);
+op_code!(SUB_SAT_S, 0x80);
+op_code!(SUB_SAT_U, 0x81);
+op_code!(MUL_SAT_S, 0x82);
+op_code!(MUL_SAT_U, 0x83);
+// ( a b -- r overflow ), the wrapped result and TRUE if it overflowed
+op_code!(ADD_OVF_S, 0x84);
+op_code!(ADD_OVF_U, 0x85);
+op_code!(SUB_OVF_S, 0x86);
+op_code!(SUB_OVF_U, 0x87);
+op_code!(MUL_OVF_S, 0x88);
+op_code!(MUL_OVF_U, 0x89);
+// high word of the 64 bit product
+op_code!(MUL_HI_S, 0x8a);
+op_code!(MUL_HI_U, 0x8b);
+// ( a b -- rem quot ), as Forth's /MOD. traps on b = 0, i32::MIN / -1 wraps around
+op_code!(DIVMOD_S, 0x8c);
+op_code!(DIVMOD_U, 0x8d);
 
 op_code!(AND, 0x58);
 op_code!(OR, 0x59);
 op_code!(XOR, 0x5a);
+// the shift count is taken mod 32
 op_code!(SHL, 0x5b);
 op_code!(SHR_S, 0x5c);
 op_code!(SHR_U, 0x5d);
@@ -95,12 +159,101 @@ op_code!(INC, 0x63);
 op_code!(DEC, 0x64);
 op_code!(ZERO, 0x65);
 
+// structured control flow, lowered to jumps by `structured::lower` before it runs.
+// `BR_DEPTH n` and `BR_IF_DEPTH n` (taken if the popped value is not 0) leave the
+// n-th enclosing block, counted from 0 for the innermost, or restart it for a `LOOP`
+op_code!(BLOCK, 0x66);
+op_code!(LOOP, 0x67);
+op_code!(IF, 0x68);
+op_code!(ELSE, 0x69);
+op_code!(END_BLOCK, 0x6a);
+op_code!(BR_DEPTH, 0x6b);
+op_code!(BR_IF_DEPTH, 0x6c);
+
+// bit counting and byte swapping, the i64 variants on two cells with the low word on top
+op_code!(CLZ, 0x6d);
+op_code!(CTZ, 0x6e);
+op_code!(POPCNT, 0x6f);
+op_code!(BSWAP, 0x70);
+op_code!(I64_CLZ, 0x71);
+op_code!(I64_CTZ, 0x72);
+op_code!(I64_POPCNT, 0x73);
+op_code!(I64_BSWAP, 0x74);
+// `value offset width BITFIELD_EXTRACT` pushes the `width` bits of value at `offset`
+// (counted from the lsb), `value field offset width BITFIELD_INSERT` replaces them with
+// the low bits of field. bits past the top are dropped
+op_code!(BITFIELD_EXTRACT, 0x75);
+op_code!(BITFIELD_INSERT, 0x76);
+// the narrow loads extending the other way, see `I32_LOAD_8_U`
+op_code!(I32_LOAD_8_S, 0x77);
+op_code!(I32_LOAD_16_U, 0x78);
+op_code!(I64_LOAD_8_S, 0x79);
+op_code!(I64_LOAD_16_S, 0x7a);
+op_code!(I64_LOAD_32_S, 0x7b);
+// sign-extend the low 8 / 16 bits of the i32 on top
+op_code!(EXTEND8_S, 0x7c);
+op_code!(EXTEND16_S, 0x7d);
+
 // TODO: i64, f32, f64
 
+/// size in bytes of the inline immediate following the opcode.
+/// for `BR_TABLE` that is the count, the table follows
+pub fn immediate_size(op: u8) -> usize {
+    match op {
+        BRI | BRZI | JMPI | JZI | CALLI | TAIL_CALLI | CATCH | BR_TABLE | I32_CONST => 4,
+        BR_DEPTH | BR_IF_DEPTH => 4,
+        ENTER | LOCAL_GET | LOCAL_SET | LOCAL_TEE | CALL_INDIRECT => 4,
+        GLOBAL_GET | GLOBAL_SET | I64_GLOBAL_GET | I64_GLOBAL_SET => 4,
+        I64_CONST => 8,
+        _ => 0,
+    }
+}
+
+/// `(pops, pushes)` on the pstack as done by `VM::step`, calls without the callee.
+/// `None` for `CALL_VM`, whose effect is the host function's, and unimplemented opcodes
+pub fn stack_effect(op: u8) -> Option<(usize, usize)> {
+    Some(match op {
+        UNREACHABLE | NOP | END | BRI | JMPI | RETURN | CALLI | TAIL_CALLI => (0, 0),
+        CATCH | END_CATCH | ENTER | LEAVE => (0, 0),
+        LOCAL_GET | GLOBAL_GET => (0, 1),
+        LOCAL_SET | GLOBAL_SET => (1, 0),
+        I64_GLOBAL_GET => (0, 2),
+        I64_GLOBAL_SET => (2, 0),
+        LOCAL_TEE => (1, 1),
+        THROW => (1, 0),
+        BRZI | JZI | BR | JMP | CALL | CALL_INDIRECT | TAIL_CALL | BR_TABLE | DROP => (1, 0),
+        BRZ | JZ => (2, 0),
+        DUP => (1, 2),
+        SWAP => (2, 2),
+        MEMORY_SIZE | I32_CONST | ZERO => (0, 1),
+        MEMORY_GROW | I32_LOAD | I32_LOAD_8 | I32_LOAD_16 => (1, 1),
+        I32_LOAD_8_S | I32_LOAD_16_U | EXTEND8_S | EXTEND16_S => (1, 1),
+        I64_LOAD_8 | I64_LOAD_16 | I64_LOAD_32 => (1, 2),
+        I64_LOAD_8_S | I64_LOAD_16_S | I64_LOAD_32_S => (1, 2),
+        I32_STORE | I32_STORE_8 | I32_STORE_16 => (2, 0),
+        EQZ | NOT | INC | DEC => (1, 1),
+        CLZ | CTZ | POPCNT | BSWAP => (1, 1),
+        I64_CLZ | I64_CTZ | I64_POPCNT | I64_BSWAP => (2, 2),
+        BITFIELD_EXTRACT => (3, 1),
+        BITFIELD_INSERT => (4, 1),
+        EQ | NE | LT_S | LT_U | GT_S | GT_U | LE_S | LE_U | GE_S | GE_U => (2, 1),
+        ADD | SUB | MUL | DIV_S | DIV_U | MOD_S | MOD_U => (2, 1),
+        ADD_SAT_S | ADD_SAT_U | SUB_SAT_S | SUB_SAT_U | MUL_SAT_S | MUL_SAT_U => (2, 1),
+        MUL_HI_S | MUL_HI_U => (2, 1),
+        ADD_OVF_S | ADD_OVF_U | SUB_OVF_S | SUB_OVF_U | MUL_OVF_S | MUL_OVF_U => (2, 2),
+        DIVMOD_S | DIVMOD_U => (2, 2),
+        AND | OR | XOR | SHL | SHR_S | SHR_U | ROTL | ROTR | MIN | MAX => (2, 1),
+        _ => return None,
+    })
+}
+
 pub fn opcode(op: u8) -> &'static str {
     match op {
         UNREACHABLE => "unreachable",
         NOP => "nop",
+        CATCH => "catch",
+        END_CATCH => "end_catch",
+        THROW => "throw",
         END => "end",
         BRI => "bri",
         BRZI => "briz",
@@ -110,11 +263,27 @@ pub fn opcode(op: u8) -> &'static str {
         CALL_VM => "call_vm",
         CALL => "call",
         CALLI => "calli",
+        CALL_INDIRECT => "call_indirect",
+        TAIL_CALL => "tail_call",
+        TAIL_CALLI => "tail_calli",
+        BR_TABLE => "br_table",
+        ENTER => "enter",
+        LEAVE => "leave",
+        LOCAL_GET => "local.get",
+        LOCAL_SET => "local.set",
+        LOCAL_TEE => "local.tee",
+        GLOBAL_GET => "global.get",
+        GLOBAL_SET => "global.set",
+        I64_GLOBAL_GET => "i64.global.get",
+        I64_GLOBAL_SET => "i64.global.set",
         DROP => "drop",
         DUP => "dup",
         SWAP => "swap",
         SELECT => "select",
 
+        MEMORY_SIZE => "memory.size",
+        MEMORY_GROW => "memory.grow",
+
         JMP => "jmp",
         JMPI => "jmpi",
         JZ => "jz",
@@ -127,6 +296,11 @@ pub fn opcode(op: u8) -> &'static str {
         I64_LOAD_8 => "i64.load_8",
         I64_LOAD_16 => "i64.load_16",
         I64_LOAD_32 => "i64.load_32",
+        I32_LOAD_8_S => "i32.load_8_s",
+        I32_LOAD_16_U => "i32.load_16_u",
+        I64_LOAD_8_S => "i64.load_8_s",
+        I64_LOAD_16_S => "i64.load_16_s",
+        I64_LOAD_32_S => "i64.load_32_s",
 
         I32_STORE => "i32.store",
         I32_STORE_8 => "i32.store_8",
@@ -172,6 +346,22 @@ pub fn opcode(op: u8) -> &'static str {
         DIV_U => "i32.div_u",
         MOD_S => "i32.mod_s",
         MOD_U => "i32.mod_u",
+        ADD_SAT_S => "i32.add_sat_s",
+        ADD_SAT_U => "i32.add_sat_u",
+        SUB_SAT_S => "i32.sub_sat_s",
+        SUB_SAT_U => "i32.sub_sat_u",
+        MUL_SAT_S => "i32.mul_sat_s",
+        MUL_SAT_U => "i32.mul_sat_u",
+        ADD_OVF_S => "i32.add_ovf_s",
+        ADD_OVF_U => "i32.add_ovf_u",
+        SUB_OVF_S => "i32.sub_ovf_s",
+        SUB_OVF_U => "i32.sub_ovf_u",
+        MUL_OVF_S => "i32.mul_ovf_s",
+        MUL_OVF_U => "i32.mul_ovf_u",
+        MUL_HI_S => "i32.mul_hi_s",
+        MUL_HI_U => "i32.mul_hi_u",
+        DIVMOD_S => "i32.divmod_s",
+        DIVMOD_U => "i32.divmod_u",
 
         AND => "i32.and",
         OR => "i32.or",
@@ -189,6 +379,27 @@ pub fn opcode(op: u8) -> &'static str {
         DEC => "i32.dec",
         ZERO => "i32.zero",
 
+        BLOCK => "block",
+        LOOP => "loop",
+        IF => "if",
+        ELSE => "else",
+        END_BLOCK => "end_block",
+        BR_DEPTH => "br_depth",
+        BR_IF_DEPTH => "br_if_depth",
+
+        CLZ => "i32.clz",
+        CTZ => "i32.ctz",
+        POPCNT => "i32.popcnt",
+        BSWAP => "i32.bswap",
+        I64_CLZ => "i64.clz",
+        I64_CTZ => "i64.ctz",
+        I64_POPCNT => "i64.popcnt",
+        I64_BSWAP => "i64.bswap",
+        BITFIELD_EXTRACT => "i32.bitfield_extract",
+        BITFIELD_INSERT => "i32.bitfield_insert",
+        EXTEND8_S => "i32.extend8_s",
+        EXTEND16_S => "i32.extend16_s",
+
         _ => "???",
     }
 }
@@ -0,0 +1,524 @@
+//! peephole optimization of code images
+//!
+//! redundant instruction sequences are removed or shortened and every static
+//! branch, jump, jump table and call target is relocated. targets computed at run
+//! time can only be relocated when they are pushed by an `I32_CONST` right before
+//! the `BR`, `JMP`, `CALL` or `TAIL_CALL` using them; any other computed target is
+//! rejected
+
+use crate::{
+    analysis::is_control,
+    decode::{Instruction, decode_range, table},
+    opcode,
+    vm::{arith_i32, compare_i32},
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OptimizeError {
+    /// the instruction at `.0` targets `.1`, inside the image but not on an instruction
+    MisalignedTarget(usize, usize),
+    /// the control transfer at `.0` does not take its target from an `I32_CONST`
+    ComputedTarget(usize),
+    /// the image ends inside the instruction at `.0`
+    Truncated(usize),
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// negative when folding replaced short code like `ZERO INC` by a longer `I32_CONST`
+    pub bytes_saved: isize,
+    pub instructions_saved: usize,
+}
+
+// immediate of a rewritten instruction, targets are still old addresses
+#[derive(Clone)]
+pub(crate) enum Imm {
+    None,
+    Value(i64),
+    Absolute(usize),
+    // encoded as `target - (addr + from)`
+    Relative { target: usize, from: usize },
+    // targets of a `BR_TABLE`, the default last
+    Table(Vec<usize>),
+}
+
+impl Imm {
+    pub(crate) fn targets(&self) -> &[usize] {
+        match self {
+            Imm::Absolute(target) | Imm::Relative { target, .. } => std::slice::from_ref(target),
+            Imm::Table(targets) => targets,
+            Imm::None | Imm::Value(_) => &[],
+        }
+    }
+}
+
+pub(crate) struct Emit {
+    pub(crate) op: u8,
+    pub(crate) imm: Imm,
+}
+
+/// optimizes `image`, loaded at address `base`. `symbols` are addresses into the image,
+/// e.g. entry points, and are relocated along with the code.
+/// the result is verified with [`check`] before it is returned
+pub fn optimize(
+    image: &[u8],
+    base: usize,
+    symbols: &mut [usize],
+) -> Result<(Vec<u8>, Stats), OptimizeError> {
+    check(image, base)?;
+    let mut current = image.to_vec();
+    let before = decode_image(image, base)?.1.len();
+    loop {
+        let next = pass(&current, base, symbols)?;
+        if next == current {
+            break;
+        }
+        current = next;
+    }
+    check(&current, base)?;
+    let stats = Stats {
+        bytes_saved: image.len() as isize - current.len() as isize,
+        instructions_saved: before - decode_image(&current, base)?.1.len(),
+    };
+    Ok((current, stats))
+}
+
+/// constant folding and dead code elimination over the control-flow graph of `image`.
+/// constants are folded on a simulated pstack within each basic block, conditional
+/// branches on a constant become unconditional or disappear and blocks no longer
+/// reachable from `symbols` are removed
+pub fn simplify(
+    image: &[u8],
+    base: usize,
+    symbols: &mut [usize],
+) -> Result<(Vec<u8>, Stats), OptimizeError> {
+    check(image, base)?;
+    let (memory, instrs) = decode_image(image, base)?;
+    let (imms, mut leaders) = immediates(&memory, &instrs, symbols)?;
+    for instr in &instrs {
+        if is_control(instr.op) {
+            leaders.insert(instr.next());
+        }
+    }
+
+    let mut blocks: BTreeMap<usize, Vec<Emit>> = BTreeMap::new();
+    let mut from = 0;
+    for i in 0..instrs.len() {
+        if i + 1 == instrs.len() || leaders.contains(&instrs[i + 1].addr) {
+            blocks.insert(instrs[from].addr, fold(&instrs[from..=i], &imms[from..=i]));
+            from = i + 1;
+        }
+    }
+
+    let mut reachable = BTreeSet::new();
+    let mut work = symbols.to_vec();
+    while let Some(start) = work.pop() {
+        if !blocks.contains_key(&start) || !reachable.insert(start) {
+            continue;
+        }
+        let code = &blocks[&start];
+        for e in code {
+            work.extend(e.imm.targets());
+        }
+        let ends = code.last().is_some_and(|e| {
+            matches!(
+                e.op,
+                opcode::UNREACHABLE
+                    | opcode::END
+                    | opcode::BRI
+                    | opcode::BR
+                    | opcode::JMPI
+                    | opcode::JMP
+                    | opcode::RETURN
+                    | opcode::TAIL_CALL
+                    | opcode::TAIL_CALLI
+                    | opcode::BR_TABLE
+            )
+        });
+        if !ends && let Some((next, _)) = blocks.range(start + 1..).next() {
+            work.push(*next);
+        }
+    }
+
+    let pieces: Vec<(usize, Vec<Emit>)> = blocks
+        .into_iter()
+        .filter(|(start, _)| reachable.contains(start))
+        .collect();
+    let emitted: usize = pieces.iter().map(|(_, code)| code.len()).sum();
+    let out = assemble(pieces, base, base + image.len(), symbols);
+    check(&out, base)?;
+    let stats = Stats {
+        bytes_saved: image.len() as isize - out.len() as isize,
+        instructions_saved: instrs.len() - emitted,
+    };
+    Ok((out, stats))
+}
+
+/// verifies that every static target inside the image lands on an instruction boundary
+pub fn check(image: &[u8], base: usize) -> Result<(), OptimizeError> {
+    let (memory, instrs) = decode_image(image, base)?;
+    let starts: BTreeSet<usize> = instrs.iter().map(|instr| instr.addr).collect();
+    for i in 0..instrs.len() {
+        let Some(imm) = static_target(&memory, &instrs, i)? else {
+            continue;
+        };
+        for target in imm.targets() {
+            if (base..base + image.len()).contains(target) && !starts.contains(target) {
+                return Err(OptimizeError::MisalignedTarget(instrs[i].addr, *target));
+            }
+        }
+    }
+    Ok(())
+}
+
+// the image laid out like memory, and its instructions
+pub(crate) fn decode_image(
+    image: &[u8],
+    base: usize,
+) -> Result<(Vec<u8>, Vec<Instruction>), OptimizeError> {
+    // decode against a buffer laid out like memory so addresses match
+    let mut memory = vec![0; base];
+    memory.extend_from_slice(image);
+    let instrs = decode_range(&memory, base, memory.len());
+    let end = instrs.last().map_or(base, |instr| instr.next());
+    if end != memory.len() {
+        return Err(OptimizeError::Truncated(end));
+    }
+    Ok((memory, instrs))
+}
+
+// immediate of `instrs[i]` if it is a control transfer, encoding its targets.
+// for an `I32_CONST` feeding a control transfer the constant is the target
+fn static_target(
+    memory: &[u8],
+    instrs: &[Instruction],
+    i: usize,
+) -> Result<Option<Imm>, OptimizeError> {
+    let instr = &instrs[i];
+    let feeds = instrs.get(i + 1).map(|next| next.op);
+    Ok(match instr.op {
+        opcode::BRI | opcode::BRZI | opcode::CALLI | opcode::TAIL_CALLI | opcode::CATCH => {
+            Some(Imm::Absolute(instr.target().unwrap()))
+        }
+        opcode::JMPI | opcode::JZI => {
+            let target = instr.target().unwrap();
+            Some(Imm::Relative { target, from: 1 })
+        }
+        opcode::BR_TABLE => Some(Imm::Table(table(memory, instr))),
+        opcode::BR | opcode::CALL | opcode::TAIL_CALL | opcode::JMP => {
+            let prev = i.checked_sub(1).map(|i| instrs[i].op);
+            if prev != Some(opcode::I32_CONST) {
+                return Err(OptimizeError::ComputedTarget(instr.addr));
+            }
+            None
+        }
+        opcode::BRZ | opcode::JZ => return Err(OptimizeError::ComputedTarget(instr.addr)),
+        opcode::I32_CONST => match feeds {
+            Some(opcode::BR | opcode::CALL | opcode::TAIL_CALL) => {
+                Some(Imm::Absolute(instr.imm_i32() as usize))
+            }
+            Some(opcode::JMP) => {
+                // relative to the `JMP` following this constant
+                let target = (instr.next() + 1).wrapping_add_signed(instr.imm_i32() as isize);
+                Some(Imm::Relative { target, from: 6 })
+            }
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+// `a op b`, `None` if `op` is no foldable binary op or would trap
+fn fold_binary(op: u8, a: i32, b: i32) -> Option<i32> {
+    Some(match op {
+        opcode::EQ
+        | opcode::NE
+        | opcode::LT_S
+        | opcode::LT_U
+        | opcode::GT_S
+        | opcode::GT_U
+        | opcode::LE_S
+        | opcode::LE_U
+        | opcode::GE_S
+        | opcode::GE_U => i32::from(compare_i32(op, a, b)),
+        opcode::ADD => a.wrapping_add(b),
+        opcode::SUB => a.wrapping_sub(b),
+        opcode::MUL => a.wrapping_mul(b),
+        opcode::DIV_S => a.checked_div(b)?,
+        opcode::DIV_U => (a as u32).checked_div(b as u32)? as i32,
+        opcode::MOD_S => a.checked_rem(b)?,
+        opcode::MOD_U => (a as u32).checked_rem(b as u32)? as i32,
+        opcode::ADD_SAT_S
+        | opcode::ADD_SAT_U
+        | opcode::SUB_SAT_S
+        | opcode::SUB_SAT_U
+        | opcode::MUL_SAT_S
+        | opcode::MUL_SAT_U
+        | opcode::MUL_HI_S
+        | opcode::MUL_HI_U => arith_i32(op, a, b),
+        opcode::AND => a & b,
+        opcode::OR => a | b,
+        opcode::XOR => a ^ b,
+        opcode::SHL => a.wrapping_shl(b as u32),
+        opcode::SHR_S => a.wrapping_shr(b as u32),
+        opcode::SHR_U => (a as u32).wrapping_shr(b as u32) as i32,
+        opcode::ROTL => a.rotate_left(b as u32),
+        opcode::ROTR => a.rotate_right(b as u32),
+        opcode::MIN => a.min(b),
+        opcode::MAX => a.max(b),
+        _ => return None,
+    })
+}
+
+fn fold_unary(op: u8, a: i32) -> Option<i32> {
+    Some(match op {
+        opcode::EQZ => i32::from(a == 0),
+        opcode::NOT => !a,
+        opcode::INC => a.wrapping_add(1),
+        opcode::DEC => a.wrapping_sub(1),
+        _ => return None,
+    })
+}
+
+// value pushed by `e` if it is a plain constant
+fn constant(e: &Emit) -> Option<i32> {
+    match (e.op, &e.imm) {
+        (opcode::I32_CONST, Imm::Value(value)) => Some(*value as i32),
+        (opcode::ZERO, _) => Some(0),
+        _ => None,
+    }
+}
+
+// rewrites a basic block. the constants at the end of the code emitted so far are
+// the known top of the pstack
+fn fold(instrs: &[Instruction], imms: &[Imm]) -> Vec<Emit> {
+    let mut out: Vec<Emit> = Vec::new();
+    let push = |out: &mut Vec<Emit>, value: i32| {
+        out.push(match value {
+            0 => Emit {
+                op: opcode::ZERO,
+                imm: Imm::None,
+            },
+            _ => Emit {
+                op: opcode::I32_CONST,
+                imm: Imm::Value(value.into()),
+            },
+        })
+    };
+    for (instr, imm) in instrs.iter().zip(imms) {
+        let top = out.last().and_then(constant);
+        let below = out.len().checked_sub(2).and_then(|i| constant(&out[i]));
+        match (instr.op, below, top) {
+            (op, Some(a), Some(b)) if fold_binary(op, a, b).is_some() => {
+                out.truncate(out.len() - 2);
+                push(&mut out, fold_binary(op, a, b).unwrap());
+            }
+            (op, _, Some(a)) if fold_unary(op, a).is_some() => {
+                out.pop();
+                push(&mut out, fold_unary(op, a).unwrap());
+            }
+            (opcode::DROP, _, Some(_)) => {
+                out.pop();
+            }
+            (opcode::SWAP, Some(_), Some(_)) => {
+                let n = out.len();
+                out.swap(n - 2, n - 1);
+            }
+            (opcode::BRZI | opcode::JZI, _, Some(condition)) => {
+                out.pop();
+                if condition == 0 {
+                    let op = if instr.op == opcode::BRZI {
+                        opcode::BRI
+                    } else {
+                        opcode::JMPI
+                    };
+                    out.push(Emit {
+                        op,
+                        imm: imm.clone(),
+                    });
+                }
+            }
+            (opcode::BR_TABLE, _, Some(index)) => {
+                out.pop();
+                let targets = imm.targets();
+                let target = targets[(index as u32 as usize).min(targets.len() - 1)];
+                out.push(Emit {
+                    op: opcode::BRI,
+                    imm: Imm::Absolute(target),
+                });
+            }
+            _ => out.push(Emit {
+                op: instr.op,
+                imm: imm.clone(),
+            }),
+        }
+    }
+    out
+}
+
+fn is_const(instr: &Instruction, value: i32) -> bool {
+    match instr.op {
+        opcode::I32_CONST => instr.imm_i32() == value,
+        opcode::ZERO => value == 0,
+        _ => false,
+    }
+}
+
+// final target of a chain of unconditional static jumps starting at `target`
+fn thread(at: &BTreeMap<usize, Instruction>, mut target: usize) -> usize {
+    let mut seen = BTreeSet::new();
+    while let Some(instr) = at.get(&target) {
+        if !matches!(instr.op, opcode::BRI | opcode::JMPI) || !seen.insert(target) {
+            break;
+        }
+        target = instr.target().unwrap();
+    }
+    target
+}
+
+// immediates of `instrs` and the addresses control can enter at
+pub(crate) fn immediates(
+    memory: &[u8],
+    instrs: &[Instruction],
+    symbols: &[usize],
+) -> Result<(Vec<Imm>, BTreeSet<usize>), OptimizeError> {
+    let mut leaders: BTreeSet<usize> = symbols.iter().copied().collect();
+    let mut imms = Vec::with_capacity(instrs.len());
+    for i in 0..instrs.len() {
+        let imm = match static_target(memory, instrs, i)? {
+            Some(imm) => {
+                leaders.extend(imm.targets());
+                imm
+            }
+            None => instrs[i].imm.map_or(Imm::None, Imm::Value),
+        };
+        imms.push(imm);
+    }
+    Ok((imms, leaders))
+}
+
+// lays out `pieces` from `base`, each being the old address of the code it replaces
+// and its instructions. targets and `symbols` are relocated through those addresses,
+// targets outside the image stay where they are
+pub(crate) fn assemble(
+    pieces: Vec<(usize, Vec<Emit>)>,
+    base: usize,
+    end: usize,
+    symbols: &mut [usize],
+) -> Vec<u8> {
+    let mut relocated = BTreeMap::new();
+    let mut addr = base;
+    for (old, code) in &pieces {
+        relocated.insert(*old, addr);
+        addr += code.iter().map(size).sum::<usize>();
+    }
+    relocated.insert(end, addr);
+
+    let relocate = |target: usize| relocated.get(&target).copied().unwrap_or(target);
+    let mut out = Vec::with_capacity(addr - base);
+    for e in pieces.iter().flat_map(|(_, code)| code) {
+        let addr = base + out.len();
+        out.push(e.op);
+        match &e.imm {
+            Imm::None => {}
+            Imm::Value(value) if opcode::immediate_size(e.op) == 8 => {
+                out.extend_from_slice(&value.to_le_bytes())
+            }
+            Imm::Value(value) => out.extend_from_slice(&(*value as i32).to_le_bytes()),
+            Imm::Absolute(target) => {
+                out.extend_from_slice(&(relocate(*target) as i32).to_le_bytes())
+            }
+            Imm::Relative { target, from } => {
+                let offset = relocate(*target).wrapping_sub(addr + from) as i32;
+                out.extend_from_slice(&offset.to_le_bytes())
+            }
+            Imm::Table(targets) => {
+                let n = targets.len() as i32 - 1;
+                out.extend_from_slice(&n.to_le_bytes());
+                for target in targets {
+                    out.extend_from_slice(&(relocate(*target) as i32).to_le_bytes());
+                }
+            }
+        }
+    }
+    for symbol in symbols.iter_mut() {
+        *symbol = relocate(*symbol);
+    }
+    out
+}
+
+// encoded size of `e`
+fn size(e: &Emit) -> usize {
+    let table = match &e.imm {
+        Imm::Table(targets) => 4 * targets.len(),
+        _ => 0,
+    };
+    1 + opcode::immediate_size(e.op) + table
+}
+
+// one round of rewriting, returns the new image
+fn pass(image: &[u8], base: usize, symbols: &mut [usize]) -> Result<Vec<u8>, OptimizeError> {
+    let (memory, instrs) = decode_image(image, base)?;
+    let at: BTreeMap<usize, Instruction> =
+        instrs.iter().map(|instr| (instr.addr, *instr)).collect();
+    // patterns must not swallow an instruction control can enter at
+    let (imms, leaders) = immediates(&memory, &instrs, symbols)?;
+
+    let mut pieces = Vec::new();
+    let mut i = 0;
+    while i < instrs.len() {
+        let instr = &instrs[i];
+        let imm = imms[i].clone();
+        let second = instrs
+            .get(i + 1)
+            .filter(|next| !leaders.contains(&next.addr) && imm.targets().is_empty());
+        let (taken, emit) = match (instr.op, second.map(|s| s.op)) {
+            (opcode::NOP, _) => (1, vec![]),
+            (opcode::DUP, Some(opcode::DROP)) | (opcode::SWAP, Some(opcode::SWAP)) => (2, vec![]),
+            (
+                _,
+                Some(
+                    opcode::ADD
+                    | opcode::SUB
+                    | opcode::OR
+                    | opcode::XOR
+                    | opcode::SHL
+                    | opcode::SHR_S
+                    | opcode::SHR_U,
+                ),
+            ) if is_const(instr, 0) => (2, vec![]),
+            (opcode::I32_CONST, Some(op @ (opcode::ADD | opcode::SUB)))
+                if is_const(instr, 1) || is_const(instr, -1) =>
+            {
+                let inc = (op == opcode::ADD) == is_const(instr, 1);
+                let op = if inc { opcode::INC } else { opcode::DEC };
+                (2, vec![Emit { op, imm: Imm::None }])
+            }
+            (opcode::I32_CONST, _) if is_const(instr, 0) && matches!(imm, Imm::Value(_)) => (
+                1,
+                vec![Emit {
+                    op: opcode::ZERO,
+                    imm: Imm::None,
+                }],
+            ),
+            (opcode::BRI | opcode::JMPI | opcode::BRZI | opcode::JZI, _) => {
+                let target = thread(&at, instr.target().unwrap());
+                let unconditional = matches!(instr.op, opcode::BRI | opcode::JMPI);
+                if unconditional && target == instr.next() {
+                    (1, vec![])
+                } else {
+                    let imm = match imm {
+                        Imm::Relative { from, .. } => Imm::Relative { target, from },
+                        _ => Imm::Absolute(target),
+                    };
+                    (1, vec![Emit { op: instr.op, imm }])
+                }
+            }
+            _ => (1, vec![Emit { op: instr.op, imm }]),
+        };
+        pieces.push((instr.addr, emit));
+        i += taken;
+    }
+    Ok(assemble(pieces, base, base + image.len(), symbols))
+}
@@ -0,0 +1,55 @@
+use std::ops::{BitOr, Range};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions(u8);
+
+impl Permissions {
+    pub const NONE: Permissions = Permissions(0);
+    pub const READ: Permissions = Permissions(1);
+    pub const WRITE: Permissions = Permissions(2);
+    pub const EXECUTE: Permissions = Permissions(4);
+    pub const RW: Permissions = Permissions(1 | 2);
+    pub const RX: Permissions = Permissions(1 | 4);
+    pub const RWX: Permissions = Permissions(1 | 2 | 4);
+
+    pub fn allows(self, access: Access) -> bool {
+        let bit = match access {
+            Access::Read => Self::READ.0,
+            Access::Write => Self::WRITE.0,
+            Access::Execute => Self::EXECUTE.0,
+        };
+        self.0 & bit != 0
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Permissions;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Permissions(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub range: Range<usize>,
+    pub permissions: Permissions,
+}
+
+impl Region {
+    pub fn contains(&self, addr: usize) -> bool {
+        self.range.contains(&addr)
+    }
+
+    pub fn overlaps(&self, range: &Range<usize>) -> bool {
+        self.range.start < range.end && range.start < self.range.end
+    }
+}
@@ -0,0 +1,140 @@
+//! lowering of wasm-style structured control flow to relative jumps
+//!
+//! code generators can emit `BLOCK`, `LOOP`, `IF`, `ELSE` and `END_BLOCK` and branch
+//! with `BR_DEPTH` / `BR_IF_DEPTH` instead of computing addresses. the nesting is
+//! checked once, when the image is loaded, and the result only uses `JMPI` and `JZI`,
+//! so it runs on the vm, the code cache, the jit and the translators as any other code:
+//!
+//! ```ignore
+//! let mut entries = [0x10];
+//! let code = structured::lower(&image, 0x10, &mut entries)?;
+//! vm.write(0x10, &code);
+//! ```
+//!
+//! lowering changes the size of the code, so the rest of the image is relocated like
+//! by [`optimize`](crate::optimize::optimize), with the same restrictions on computed targets
+
+use crate::{
+    decode::Instruction,
+    opcode,
+    optimize::{Emit, Imm, OptimizeError, assemble, decode_image, immediates},
+};
+use std::collections::BTreeMap;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StructureError {
+    /// the `ELSE` or `END_BLOCK` at the address closes no block, or the `ELSE` no `IF`
+    Unmatched(usize),
+    /// the block opened at the address is still open at the end of the image
+    Unclosed(usize),
+    /// the branch at `addr` leaves more blocks than it is nested in
+    Depth { addr: usize, depth: usize },
+    /// the rest of the image can not be relocated
+    Image(OptimizeError),
+}
+
+impl From<OptimizeError> for StructureError {
+    fn from(e: OptimizeError) -> Self {
+        StructureError::Image(e)
+    }
+}
+
+// where the blocks opened at an address end, and the `ELSE` of an `IF`
+struct Blocks {
+    ends: BTreeMap<usize, usize>,
+    elses: BTreeMap<usize, usize>,
+}
+
+/// lowers the structured control flow of `image`, loaded at address `base`.
+/// `symbols` are addresses into the image and are relocated along with the code
+pub fn lower(image: &[u8], base: usize, symbols: &mut [usize]) -> Result<Vec<u8>, StructureError> {
+    let (memory, instrs) = decode_image(image, base)?;
+    let (imms, _) = immediates(&memory, &instrs, symbols)?;
+    let blocks = match_blocks(&instrs)?;
+
+    let jump = |op: u8, target: usize| Emit {
+        op,
+        imm: Imm::Relative { target, from: 1 },
+    };
+    // the enclosing blocks, innermost last
+    let mut open: Vec<(u8, usize)> = Vec::new();
+    let mut pieces = Vec::new();
+    for (instr, imm) in instrs.iter().zip(imms) {
+        let code = match instr.op {
+            opcode::BLOCK | opcode::LOOP => {
+                open.push((instr.op, instr.addr));
+                vec![]
+            }
+            opcode::IF => {
+                open.push((instr.op, instr.addr));
+                // the false branch starts after the `ELSE`
+                let target = match blocks.elses.get(&instr.addr) {
+                    Some(addr) => addr + 1,
+                    None => blocks.ends[&instr.addr],
+                };
+                vec![jump(opcode::JZI, target)]
+            }
+            opcode::ELSE => {
+                let (_, start) = open.last().unwrap();
+                vec![jump(opcode::JMPI, blocks.ends[start])]
+            }
+            opcode::END_BLOCK => {
+                open.pop();
+                vec![]
+            }
+            opcode::BR_DEPTH | opcode::BR_IF_DEPTH => {
+                let depth = instr.imm_i32() as u32 as usize;
+                let Some((op, start)) = open.len().checked_sub(depth + 1).map(|i| open[i]) else {
+                    let addr = instr.addr;
+                    return Err(StructureError::Depth { addr, depth });
+                };
+                // a loop is restarted, any other block left
+                let target = if op == opcode::LOOP {
+                    start
+                } else {
+                    blocks.ends[&start]
+                };
+                if instr.op == opcode::BR_DEPTH {
+                    vec![jump(opcode::JMPI, target)]
+                } else {
+                    let eqz = Emit {
+                        op: opcode::EQZ,
+                        imm: Imm::None,
+                    };
+                    vec![eqz, jump(opcode::JZI, target)]
+                }
+            }
+            op => vec![Emit { op, imm }],
+        };
+        pieces.push((instr.addr, code));
+    }
+    Ok(assemble(pieces, base, base + image.len(), symbols))
+}
+
+fn match_blocks(instrs: &[Instruction]) -> Result<Blocks, StructureError> {
+    let mut blocks = Blocks {
+        ends: BTreeMap::new(),
+        elses: BTreeMap::new(),
+    };
+    let mut open: Vec<(u8, usize)> = Vec::new();
+    for instr in instrs {
+        match instr.op {
+            opcode::BLOCK | opcode::LOOP | opcode::IF => open.push((instr.op, instr.addr)),
+            opcode::ELSE => match open.last() {
+                Some((opcode::IF, start)) if !blocks.elses.contains_key(start) => {
+                    blocks.elses.insert(*start, instr.addr);
+                }
+                _ => return Err(StructureError::Unmatched(instr.addr)),
+            },
+            opcode::END_BLOCK => {
+                let (_, start) = open.pop().ok_or(StructureError::Unmatched(instr.addr))?;
+                blocks.ends.insert(start, instr.addr);
+            }
+            _ => {}
+        }
+    }
+    match open.last() {
+        Some((_, start)) => Err(StructureError::Unclosed(*start)),
+        None => Ok(blocks),
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{
+    analysis::{Cfg, Edge, StackEffect, StackError},
+    opcode::*,
+};
+
+const PROGRAM: [u8; 23] = [
+    ZERO, // 16
+    BRZI, // 17
+    28, 0, 0, 0,     // 18 - 21
+    CALLI, // 22
+    36, 0, 0, 0,         // 23 - 26
+    END,       // 27
+    I32_CONST, // 28
+    36, 0, 0, 0,    // 29 - 32
+    CALL, // 33
+    BR,   // 34
+    END,  // 35
+    // fn square
+    DUP,    // 36
+    MUL,    // 37
+    RETURN, // 38
+];
+
+#[test]
+fn test_cfg() {
+    let mut memory = vec![0; 16];
+    memory.extend_from_slice(&PROGRAM);
+    let cfg = Cfg::new(&memory, 16, memory.len(), &[16]);
+
+    let starts: Vec<usize> = cfg.blocks.keys().copied().collect();
+    assert_eq!(vec![16, 22, 27, 28, 34, 35, 36], starts);
+    assert_eq!(vec![Edge::To(28), Edge::To(22)], cfg.blocks[&16].successors);
+    assert_eq!(Some(Edge::To(36)), cfg.blocks[&22].call);
+    assert_eq!(Some(Edge::Unresolved), cfg.blocks[&28].call);
+    assert_eq!(vec![Edge::Unresolved], cfg.blocks[&34].successors);
+
+    let main = &cfg.functions[&16];
+    let blocks: Vec<usize> = main.blocks.iter().copied().collect();
+    assert_eq!(vec![16, 22, 27, 28, 34], blocks);
+    assert_eq!(vec![Edge::To(36), Edge::Unresolved], main.calls);
+    assert_eq!(
+        vec![36],
+        cfg.functions[&36]
+            .blocks
+            .iter()
+            .copied()
+            .collect::<Vec<_>>()
+    );
+
+    let dot = cfg.cfg_dot();
+    assert!(dot.contains("    b_24 [label=\"0x24:\\l  dup\\l  i32.mul\\l  return\\l\"];\n"));
+    assert!(dot.contains("    b_10 -> b_1c;\n    b_10 -> b_16;\n"));
+    assert!(dot.contains("    b_22 -> unresolved [style=dashed];\n"));
+
+    let expected = "digraph calls {
+    node [shape=box, fontname=monospace];
+    f_10 [label=\"fn 0x10\"];
+    f_24 [label=\"fn 0x24\"];
+    f_10 -> f_24;
+    f_10 -> unresolved [style=dashed];
+    unresolved [label=\"?\", shape=circle];
+}
+";
+    assert_eq!(expected, cfg.call_graph_dot());
+}
+
+const EFFECTS: [u8; 49] = [
+    // fn square
+    DUP,    // 16
+    MUL,    // 17
+    RETURN, // 18
+    // fn sum_squares
+    CALLI, // 19
+    16,
+    0,
+    0,
+    0,     // 20 - 23
+    SWAP,  // 24
+    CALLI, // 25
+    16,
+    0,
+    0,
+    0,      // 26 - 29
+    ADD,    // 30
+    RETURN, // 31
+    // fn host, calls host function 0
+    I32_CONST, // 32
+    0,
+    0,
+    0,
+    0,       // 33 - 36
+    CALL_VM, // 37
+    RETURN,  // 38
+    // fn mismatch
+    BRZI, // 39
+    50,
+    0,
+    0,
+    0,         // 40 - 43
+    I32_CONST, // 44
+    1,
+    0,
+    0,
+    0,      // 45 - 48
+    RETURN, // 49
+    RETURN, // 50
+    // fn computed
+    BR, // 51
+    // fn recursive
+    CALLI, // 52
+    52,
+    0,
+    0,
+    0,      // 53 - 56
+    RETURN, // 57
+    // fn indirect, the callee's signature is ( a b c -- d )
+    ZERO,          // 58
+    CALL_INDIRECT, // 59
+    3,
+    0,
+    1,
+    0,      // 60 - 63
+    RETURN, // 64
+];
+
+#[test]
+fn test_stack_effects() {
+    let mut memory = vec![0; 16];
+    memory.extend_from_slice(&EFFECTS);
+    let cfg = Cfg::new(&memory, 16, memory.len(), &[19, 32, 39, 51, 52, 58]);
+    let effects = cfg.stack_effects(&[(1, 2)]);
+
+    let square = effects[&16].unwrap();
+    assert_eq!("( a -- b )", square.to_string());
+    assert_eq!(2, square.max_depth);
+
+    let expected = StackEffect {
+        params: 2,
+        results: 1,
+        max_depth: 3,
+    };
+    assert_eq!(Ok(expected), effects[&19]);
+    assert_eq!("( a b -- c )", expected.to_string());
+
+    let host = effects[&32].unwrap();
+    assert_eq!("( a -- b c )", host.to_string());
+    assert_eq!(2, host.max_depth);
+
+    let depths = (0, -1);
+    assert_eq!(Err(StackError::Mismatch { addr: 50, depths }), effects[&39]);
+    assert_eq!(Err(StackError::Unknown(51)), effects[&51]);
+    assert_eq!(Err(StackError::Recursive(52)), effects[&52]);
+    assert_eq!("( a b c -- d )", effects[&58].unwrap().to_string());
+}
+
+#[test]
+fn test_stack_effects_outside_range() {
+    let mut memory = vec![0; 16];
+    memory.extend_from_slice(&[CALLI, 0, 1, 0, 0, END, TAIL_CALLI, 0, 2, 0, 0]);
+    let cfg = Cfg::new(&memory, 16, memory.len(), &[16, 22]);
+    let effects = cfg.stack_effects(&[]);
+
+    assert_eq!(Err(StackError::Unknown(16)), effects[&16]);
+    assert_eq!(Err(StackError::Unknown(22)), effects[&22]);
+    assert_eq!(Err(StackError::Unknown(0x100)), effects[&0x100]);
+}
@@ -0,0 +1,200 @@
+use super::{MEMSIZE, PSTACK, RSTACK, create_vm, programs::PROGRAMS};
+use crate::{Result, Trap, VM, ValueType, VmError, aot};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{fs, process::Command};
+
+// (name, entry, args) of a translated function, run with the args pushed
+pub(super) type Case<'a> = (&'a str, usize, &'a [i32]);
+
+// the code the C harness exits with for the result of `VM::run`
+fn exit_code(result: &Result<()>) -> i32 {
+    let trap = match result {
+        Ok(()) => return 0,
+        Err(VmError::UnknownVmFn { .. }) => return 3,
+        Err(VmError::Trap { trap, .. }) => trap,
+        Err(e) => panic!("{e:?}"),
+    };
+    match trap {
+        Trap::Unreachable => 1,
+        Trap::UnknownOp => 2,
+        Trap::Uncaught { .. } => 6,
+        Trap::JumpOutOfRange { .. } => 7,
+        Trap::NoFrame => 8,
+        Trap::BadLocal { .. } => 9,
+        Trap::BadGlobal { .. } => 10,
+        Trap::ImmutableGlobal { .. } => 11,
+        Trap::TableOutOfRange { .. } => 12,
+        Trap::SignatureMismatch { .. } => 13,
+        Trap::DivisionByZero => 14,
+        Trap::LocalsOverflow { .. } => 15,
+        _ => panic!("{trap:?}"),
+    }
+}
+
+fn create_c_vm(code: &[u8], setup: fn(&mut VM)) -> VM {
+    let mut vm = create_vm();
+    vm.add_function(&|vm: &mut VM| {
+        let a = vm.pop_i32();
+        vm.push_i32(a.wrapping_mul(2));
+    });
+    setup(&mut vm);
+    vm.write(16, code);
+    vm
+}
+
+// "code ip stack.. cell" as printed by the C harness
+fn interpret(code: &[u8], setup: fn(&mut VM), entry: usize, args: &[i32]) -> String {
+    let mut vm = create_c_vm(code, setup);
+    for arg in args {
+        vm.push_i32(*arg);
+    }
+    let mut ip = entry;
+    let result = vm.run(&mut ip);
+    let mut line = format!("{} {ip}", exit_code(&result));
+    while vm.read_i32(0) != PSTACK as i32 {
+        line += &format!(" {}", vm.pop_i32());
+    }
+    line + &format!(" {}", vm.read_i32(0x100))
+}
+
+// a main running every case on a fresh vm with the globals and table of `vm`
+fn harness(code: &[u8], vm: &VM, cases: &[Case]) -> String {
+    let bytes: Vec<String> = code.iter().map(|b| b.to_string()).collect();
+    let globals: Vec<String> = vm
+        .globals()
+        .iter()
+        .map(|g| {
+            let is_i64 = u8::from(g.ty == ValueType::I64);
+            format!(
+                "{{ INT64_C({}), {is_i64}, {} }}",
+                g.value,
+                u8::from(g.mutable)
+            )
+        })
+        .collect();
+    let table: Vec<String> = vm
+        .table()
+        .iter()
+        .map(|t| format!("{{ {}, {:#x} }}", t.entry, t.signature.encode()))
+        .collect();
+    let mut main = format!(
+        r#"
+#include <stdio.h>
+
+static void host_double(toyvm *vm) {{
+    toyvm_push_i32(vm, (int32_t)((uint32_t)toyvm_pop_i32(vm) * 2));
+}}
+
+static const uint8_t harness_image[] = {{ {} }};
+static const toyvm_global harness_globals[] = {{ {} }};
+static toyvm_table_entry harness_table[] = {{ {} }};
+
+static void run(int (*entry)(toyvm *), const int32_t *args, int n) {{
+    static toyvm_fn functions[] = {{ host_double }};
+    toyvm vm = {{ 0 }};
+    vm.memory = calloc({MEMSIZE}, 1);
+    vm.memory_len = {MEMSIZE};
+    vm.pstack_top = 0;
+    vm.rstack_top = 4;
+    vm.functions = functions;
+    vm.functions_len = 1;
+    toyvm_global globals[sizeof harness_globals / sizeof harness_globals[0]];
+    memcpy(globals, harness_globals, sizeof globals);
+    vm.globals = globals;
+    vm.globals_len = {};
+    vm.table = harness_table;
+    vm.table_len = {};
+    memcpy(vm.memory + 16, harness_image, sizeof harness_image);
+    int32_t cell = {PSTACK};
+    memcpy(vm.memory, &cell, 4);
+    cell = {RSTACK};
+    memcpy(vm.memory + 4, &cell, 4);
+    for (int i = 0; i < n; i++) toyvm_push_i32(&vm, args[i]);
+    int code = entry(&vm);
+    printf("%d %u", code, vm.ip);
+    for (;;) {{
+        memcpy(&cell, vm.memory, 4);
+        if (cell == {PSTACK}) break;
+        printf(" %d", toyvm_pop_i32(&vm));
+    }}
+    memcpy(&cell, vm.memory + 0x100, 4);
+    printf(" %d\n", cell);
+    free(vm.memory);
+}}
+
+int main(void) {{
+"#,
+        bytes.join(", "),
+        // C has no empty initializers
+        if globals.is_empty() {
+            "{ 0 }".to_string()
+        } else {
+            globals.join(", ")
+        },
+        if table.is_empty() {
+            "{ 0 }".to_string()
+        } else {
+            table.join(", ")
+        },
+        globals.len(),
+        table.len(),
+    );
+    for (name, _, args) in cases {
+        let args: Vec<String> = args.iter().map(|a| format!("{a},")).collect();
+        main += &format!(
+            "    {{ static const int32_t args[] = {{ {} 0 }}; run({name}, args, {}); }}\n",
+            args.concat(),
+            args.len()
+        );
+    }
+    main + "    return 0;\n}\n"
+}
+
+/// translates `code`, loaded at 16, to C with a function for every case, compiles and
+/// runs them and checks each ends as when interpreted: with the same error code, ip,
+/// pstack and cell at 0x100. `setup` declares the globals and the function table
+fn check(code: &[u8], setup: fn(&mut VM), cases: &[Case]) {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let vm = create_c_vm(code, setup);
+    let entries: Vec<(&str, usize)> = cases.iter().map(|(n, a, _)| (*n, *a)).collect();
+    let source = aot::c::translate(code, 16, &entries) + &harness(code, &vm, cases);
+
+    let dir = std::env::temp_dir().join(format!(
+        "toyvm-aot-c-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    let c_file = dir.join("image.c");
+    let exe = dir.join("image");
+    fs::write(&c_file, source).unwrap();
+    let cc = Command::new("cc")
+        .arg("-O1")
+        .arg("-o")
+        .arg(&exe)
+        .arg(&c_file)
+        .output()
+        .unwrap_or_else(|e| panic!("the C translation tests need a C compiler, running cc: {e}"));
+    let stderr = String::from_utf8_lossy(&cc.stderr);
+    assert!(cc.status.success(), "{}: {stderr}", c_file.display());
+    let output = Command::new(&exe).output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(cases.len(), lines.len());
+    for ((name, entry, args), line) in cases.iter().zip(lines) {
+        assert_eq!(interpret(code, setup, *entry, args), line, "{name}");
+    }
+}
+
+/// `check` for the program `name` of `PROGRAMS`
+pub(super) fn check_program(name: &str) {
+    let program = PROGRAMS.iter().find(|p| p.name == name).unwrap();
+    check(program.code, program.setup, program.cases);
+}
+
+#[test]
+fn test_translate_c() {
+    check_program("basics");
+}
@@ -0,0 +1,124 @@
+use super::{PSTACK, create_vm};
+use crate::{VM, opcode::*};
+use std::panic::{self, AssertUnwindSafe};
+
+// ( x c -- r ) scrambles x c times, then mixes in the cell at 0x100
+const FUNCTION: [u8; 59] = [
+    SWAP,      // 64
+    DUP,       // 65
+    I32_CONST, // 66
+    3, 0, 0, 0,         // 67 - 70
+    SHR_U,     // 71
+    XOR,       // 72
+    I32_CONST, // 73
+    31, 0, 0, 0,         // 74 - 77
+    MUL,       // 78
+    I32_CONST, // 79
+    7, 0, 0, 0,         // 80 - 83
+    ADD,       // 84
+    I32_CONST, // 85
+    0xfb, 0xff, 0xff, 0xff, // 86 - 89
+    ROTL, // 90
+    SWAP, // 91
+    DEC,  // 92
+    DUP,  // 93
+    BRZI, // 94
+    105, 0, 0, 0,    // 95 - 98
+    SWAP, // 99
+    BRI,  // 100
+    65, 0, 0, 0,         // 101 - 104
+    DROP,      // 105
+    I32_CONST, // 106
+    0, 1, 0, 0,         // 107 - 110
+    I32_LOAD,  // 111
+    ADD,       // 112
+    DUP,       // 113
+    I32_CONST, // 114
+    10, 0, 0, 0,      // 115 - 118
+    GT_S,   // 119
+    ADD,    // 120
+    MIN,    // 121
+    RETURN, // 122
+];
+
+fn create_jit_vm(jit: bool) -> VM {
+    let mut vm = create_vm();
+    vm.write(64, &FUNCTION);
+    vm.write_i32(-77, 0x100);
+    if jit {
+        vm.enable_jit(1);
+    }
+    vm
+}
+
+fn call(vm: &mut VM, limit: i32, x: i32, c: i32) -> (usize, i32) {
+    let mut main = vec![I32_CONST];
+    main.extend_from_slice(&limit.to_le_bytes());
+    main.push(I32_CONST);
+    main.extend_from_slice(&x.to_le_bytes());
+    main.push(I32_CONST);
+    main.extend_from_slice(&c.to_le_bytes());
+    main.extend_from_slice(&[CALLI, 64, 0, 0, 0, END]);
+    vm.write(16, &main);
+
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    let result = vm.pop_i32();
+    assert_eq!(PSTACK as i32, vm.read_i32(0));
+    (ip, result)
+}
+
+#[test]
+fn test_jit_matches_interpreter() {
+    let mut interpreter = create_jit_vm(false);
+    let mut jit = create_jit_vm(true);
+    for (limit, x, c) in [
+        (i32::MAX, 1, 1),
+        (i32::MAX, 12345, 17),
+        (1000, -1, 100),
+        (i32::MIN, i32::MAX, 3),
+        (i32::MAX, i32::MIN, 1000),
+    ] {
+        assert_eq!(
+            call(&mut interpreter, limit, x, c),
+            call(&mut jit, limit, x, c)
+        );
+    }
+    assert_eq!(1, jit.jit_compiled());
+}
+
+#[test]
+fn test_jit_invalidation() {
+    let mut vm = create_jit_vm(true);
+    let before = call(&mut vm, i32::MAX, 5, 5);
+    assert_eq!(1, vm.jit_compiled());
+
+    // patch `I32_CONST 31` into `I32_CONST 33`
+    vm.write_u8(33, 74);
+    assert_eq!(0, vm.jit_compiled());
+    let after = call(&mut vm, i32::MAX, 5, 5);
+    assert_ne!(before, after);
+    assert_eq!(1, vm.jit_compiled());
+
+    let mut interpreter = create_jit_vm(false);
+    interpreter.write_u8(33, 74);
+    assert_eq!(after, call(&mut interpreter, i32::MAX, 5, 5));
+}
+
+#[test]
+fn test_jit_corrupt_pstack_pointer() {
+    let mut vm = create_jit_vm(true);
+    call(&mut vm, i32::MAX, 5, 5);
+    assert_eq!(1, vm.jit_compiled());
+
+    // a pstack pointer of -4 stored by guest code, then the hot function
+    let main = [
+        I32_CONST, 0xfc, 0xff, 0xff, 0xff, I32_CONST, 0, 0, 0, 0, I32_STORE, CALLI, 64, 0, 0, 0,
+        END,
+    ];
+    vm.write(16, &main);
+    let mut ip = 16;
+    // the interpreter panics on it, the native code must not run off the memory
+    let result = panic::catch_unwind(AssertUnwindSafe(|| vm.run(&mut ip)));
+    assert!(result.is_err());
+}
@@ -1,4 +1,16 @@
-use crate::{VM, opcode::*};
+mod analysis;
+mod aot_c;
+#[cfg(feature = "jit")]
+mod jit;
+mod optimize;
+mod programs;
+mod structured;
+
+use crate::{
+    Access, ErrorKind, FALSE, PAGE_SIZE, Permissions, Signature, TRUE, Trap, VM, ValueType,
+    VmError, decode::decode_range, opcode::*,
+};
+use programs::SUM_LOOP;
 
 const MEMSIZE: usize = 0x4000;
 const PSTACK: usize = 0x2000;
@@ -88,3 +100,1330 @@ fn test_calls() {
     let result = vm.pop_i32();
     assert_eq!(16, result);
 }
+
+#[test]
+fn test_memory_grow() {
+    let mut vm = create_vm();
+    assert_eq!(MEMSIZE / PAGE_SIZE, vm.memory_size());
+
+    // ( -- pages old_pages )
+    let program = [MEMORY_SIZE, I32_CONST, 2, 0, 0, 0, MEMORY_GROW, END];
+    vm.write(16, &program);
+
+    // growth is denied until the host raises the limit
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(-1, vm.pop_i32());
+    assert_eq!(4, vm.pop_i32());
+    assert_eq!(MEMSIZE, vm.memory_ref().len());
+
+    vm.set_max_pages(8);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(4, vm.pop_i32());
+    assert_eq!(4, vm.pop_i32());
+    assert_eq!(6, vm.memory_size());
+    assert_eq!(MEMSIZE + 2 * PAGE_SIZE, vm.memory_ref().len());
+
+    assert_eq!(None, vm.grow_memory(3));
+    assert_eq!(Some(6), vm.grow_memory(2));
+    assert_eq!(8, vm.memory_size());
+
+    // memory of a partial page is padded to a whole one
+    let mut vm = VM::new(vec![0; PAGE_SIZE + 1], Vec::new(), 0, 4);
+    assert_eq!(2, vm.memory_size());
+    assert_eq!(2 * PAGE_SIZE, vm.memory_ref().len());
+    vm.set_max_pages(3);
+    assert_eq!(Some(2), vm.grow_memory(1));
+    assert_eq!(3 * PAGE_SIZE, vm.memory_ref().len());
+}
+
+fn create_vm_with_regions() -> VM {
+    let mut vm = create_vm();
+    vm.add_region("cells", 0, 16, Permissions::RW).unwrap();
+    vm.add_region("code", 16, 0x100 - 16, Permissions::RX)
+        .unwrap();
+    vm.add_region("data", 0x100, 0x1000 - 0x100, Permissions::RW)
+        .unwrap();
+    vm.add_guard("pstack guard", 0x1000, 0x100).unwrap();
+    vm.add_region("pstack", 0x1100, PSTACK + 4 - 0x1100, Permissions::RW)
+        .unwrap();
+    vm.add_guard("rstack guard", PSTACK + 4, 0x100).unwrap();
+    vm.add_region(
+        "rstack",
+        PSTACK + 0x104,
+        MEMSIZE - PSTACK - 0x104,
+        Permissions::RW,
+    )
+    .unwrap();
+    vm
+}
+
+#[test]
+fn test_regions() {
+    let mut vm = create_vm();
+    vm.add_region("code", 16, 0x100, Permissions::RX).unwrap();
+    assert!(matches!(
+        vm.add_region("data", 0x100, 0x100, Permissions::RW),
+        Err(VmError::RegionOverlap {
+            start: 0x100,
+            end: 0x200
+        })
+    ));
+    assert_eq!(
+        Err(VmError::RegionOutOfBounds {
+            start: MEMSIZE - 0x10,
+            len: 0x20
+        }),
+        vm.add_region("tail", MEMSIZE - 0x10, 0x20, Permissions::RW)
+    );
+    assert!(vm.add_guard("wrap", 0x200, usize::MAX).is_err());
+    vm.add_guard("tail", MEMSIZE - 0x10, 0x10).unwrap();
+
+    let mut vm = create_vm_with_regions();
+    assert_eq!("code", vm.region_at(16).unwrap().name);
+    assert!(vm.region_at(MEMSIZE).is_none());
+
+    // store into the code region
+    let program = [
+        I32_CONST, // 16
+        0x2a, 0, 0, 0,         // 17 - 20
+        I32_CONST, // 21
+        16, 0, 0, 0,         // 22 - 25
+        I32_STORE, // 26
+        END,       // 27
+    ];
+    vm.write(16, &program);
+    let mut ip = 16;
+    let r = vm.run(&mut ip);
+    assert!(matches!(
+        r,
+        Err(VmError::Trap {
+            trap: Trap::PermissionDenied {
+                addr: 16,
+                access: Access::Write
+            },
+            ..
+        })
+    ));
+
+    // stores into data are fine, executing it is not
+    vm.write(22, &[0, 1, 0, 0]);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(0x2a, vm.read_i32(0x100));
+
+    let program = [BRI, 0, 1, 0, 0];
+    vm.write(16, &program);
+    let mut ip = 16;
+    let r = vm.run(&mut ip);
+    assert!(matches!(
+        r,
+        Err(VmError::Trap {
+            trap: Trap::PermissionDenied {
+                addr: 0x100,
+                access: Access::Execute
+            },
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_stack_guard() {
+    let mut vm = create_vm_with_regions();
+
+    // pstack is almost full
+    vm.write_i32(0x1104, 0);
+    let program = [
+        ZERO, // 16
+        ZERO, // 17
+        ZERO, // 18
+        END,  // 19
+    ];
+    vm.write(16, &program);
+    let mut ip = 16;
+    let r = vm.run(&mut ip);
+    assert!(matches!(
+        r,
+        Err(VmError::Trap {
+            trap: Trap::PermissionDenied {
+                addr: 0x10fc,
+                access: Access::Write
+            },
+            ..
+        })
+    ));
+    assert_eq!(19, ip);
+
+    // popping from an empty rstack reads past the end of mapped memory
+    let mut vm = create_vm_with_regions();
+    vm.write(16, &[RETURN]);
+    let mut ip = 16;
+    let r = vm.run(&mut ip);
+    assert!(matches!(
+        r,
+        Err(VmError::Trap {
+            trap: Trap::PermissionDenied {
+                addr: MEMSIZE,
+                access: Access::Read
+            },
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_private_rstack() {
+    let mut vm = create_vm();
+    vm.enable_private_rstack(2);
+
+    let program = [
+        I32_CONST, // 16
+        26, 0, 0, 0,    // 17 - 20
+        CALL, // 21
+        END,  // 22
+        // fn square
+        DUP,    // 23
+        MUL,    // 24
+        RETURN, // 25
+        // fn quad
+        CALLI, // 26
+        23, 0, 0, 0,     // 27 - 30
+        CALLI, // 31
+        23, 0, 0, 0,      // 32 - 35
+        RETURN, // 36
+    ];
+    vm.write(16, &program);
+    vm.push_i32(3);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(81, vm.pop_i32());
+    assert_eq!(Some(&[][..]), vm.private_rstack());
+    // the rstack in memory is untouched
+    assert_eq!(RSTACK as i32, vm.read_i32(4));
+
+    // with a depth of 1, the nested call to square overflows
+    vm.enable_private_rstack(1);
+    vm.push_i32(3);
+    let mut ip = 16;
+    let r = vm.run(&mut ip);
+    assert!(matches!(
+        r,
+        Err(VmError::Trap {
+            trap: Trap::CallStackOverflow { max_depth: 1 },
+            ..
+        })
+    ));
+
+    vm.enable_private_rstack(1);
+    vm.write(16, &[RETURN]);
+    let mut ip = 16;
+    let r = vm.run(&mut ip);
+    assert!(matches!(
+        r,
+        Err(VmError::Trap {
+            trap: Trap::CallStackUnderflow,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_stack_pointer_cells() {
+    let mut vm = create_vm();
+    let double = vm.add_function(&|vm: &mut VM| {
+        let a = vm.pop_i32();
+        vm.push_i32(a * 2);
+    });
+
+    let program = [
+        I32_CONST, // 16
+        21,
+        0,
+        0,
+        0,         // 17 - 20
+        I32_CONST, // 21
+        double as u8,
+        0,
+        0,
+        0,         // 22 - 25
+        CALL_VM,   // 26
+        I32_CONST, // 27
+        0,
+        0,
+        0,
+        0,        // 28 - 31
+        I32_LOAD, // 32
+        END,      // 33
+    ];
+    vm.write(16, &program);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+
+    // the guest sees the stack pointer after popping the address
+    assert_eq!(PSTACK as i32 - 4, vm.pop_i32());
+    assert_eq!(42, vm.pop_i32());
+
+    // a guest store to the cell moves the stack
+    let program = [
+        I32_CONST, // 16
+        0x00, 0x1f, 0, 0,         // 17 - 20
+        I32_CONST, // 21
+        0, 0, 0, 0,         // 22 - 25
+        I32_STORE, // 26
+        ZERO,      // 27
+        END,       // 28
+    ];
+    vm.write(16, &program);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(0x1f00 - 4, vm.read_i32(0));
+}
+
+#[test]
+fn test_code_cache() {
+    let mut vm = create_vm();
+    vm.write(16, SUM_LOOP);
+    vm.enable_code_cache(16, SUM_LOOP.len());
+
+    vm.push_i32(10);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(55, vm.read_i32(0x100));
+    assert_eq!(44, ip);
+    assert_eq!(PSTACK as i32, vm.read_i32(0));
+
+    // errors report the same ip as without the cache
+    vm.push_i32(1);
+    vm.write(36, &[UNREACHABLE]);
+    let mut ip = 16;
+    let r = vm.run(&mut ip);
+    assert_eq!(37, ip);
+    assert_eq!(36, r.unwrap_err().context().unwrap().ip);
+}
+
+#[test]
+fn test_code_cache_invalidation() {
+    let mut vm = create_vm();
+    let program = [
+        I32_CONST, // 16
+        7, 0, 0, 0,         // 17 - 20
+        I32_CONST, // 21
+        99, 0, 0, 0,         // 22 - 25
+        I32_CONST, // 26
+        17, 0, 0, 0,         // 27 - 30
+        I32_STORE, // 31
+        END,       // 32
+    ];
+    vm.write(16, &program);
+    vm.enable_code_cache(16, program.len());
+
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(7, vm.pop_i32());
+
+    // the guest store patched the first immediate
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(99, vm.pop_i32());
+
+    // and so do host writes
+    vm.write_i32(5, 17);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(5, vm.pop_i32());
+    assert_eq!(99, vm.read_i32(17));
+}
+
+#[test]
+fn test_code_cache_step() {
+    let program = [
+        ENTER, // 16
+        2, 0, 0, 0,         // 17 - 20
+        I32_CONST, // 21
+        0xfa, 0xff, 0xff, 0xff,      // 22 - 25
+        LOCAL_TEE, // 26
+        0, 0, 0, 0,         // 27 - 30
+        ZERO,      // 31
+        SWAP,      // 32
+        DUP,       // 33
+        LOCAL_GET, // 34
+        0, 0, 0, 0,         // 35 - 38
+        MUL,       // 39
+        ADD,       // 40
+        INC,       // 41
+        DEC,       // 42
+        NOT,       // 43
+        DUP,       // 44
+        I32_CONST, // 45
+        3, 0, 0, 0,         // 46 - 49
+        SHR_U,     // 50
+        LOCAL_SET, // 51
+        1, 0, 0, 0,         // 52 - 55
+        I32_CONST, // 56
+        5, 0, 0, 0,         // 57 - 60
+        MIN,       // 61
+        DUP,       // 62
+        LT_U,      // 63
+        EQZ,       // 64
+        I32_CONST, // 65
+        0, 1, 0, 0,         // 66 - 69
+        I32_STORE, // 70
+        I32_CONST, // 71
+        0, 1, 0, 0,         // 72 - 75
+        I32_LOAD,  // 76
+        LOCAL_GET, // 77
+        1, 0, 0, 0,         // 78 - 81
+        ADD_SAT_S, // 82
+        MUL_HI_U,  // 83
+        DROP,      // 84
+        LEAVE,     // 85
+        CALLI,     // 86
+        92, 0, 0, 0,         // 87 - 90
+        END,       // 91
+        I32_CONST, // 92
+        9, 0, 0, 0,      // 93 - 96
+        RETURN, // 97
+    ];
+    // ip and pstack after every step, then the pstack
+    let trace = |cached: bool| {
+        let mut vm = create_vm();
+        vm.write(16, &program);
+        if cached {
+            vm.enable_code_cache(16, program.len());
+        }
+        let mut trace = Vec::new();
+        let mut ip = 16;
+        while vm.step(&mut ip).unwrap() {
+            trace.push((ip, vm.read_i32(0)));
+        }
+        let mut stack = Vec::new();
+        while vm.read_i32(0) != PSTACK as i32 {
+            stack.push(vm.pop_i32());
+        }
+        (trace, stack)
+    };
+    let (steps, stack) = trace(true);
+    // the fused I32_CONST; I32_LOAD at 71 still takes two steps
+    assert_eq!(33, steps.len());
+    assert_eq!(vec![9], stack);
+    assert_eq!(trace(false), (steps, stack));
+}
+
+#[test]
+fn test_decode() {
+    let mut vm = create_vm();
+    vm.write(16, SUM_LOOP);
+    let code = decode_range(vm.memory_ref(), 16, 16 + SUM_LOOP.len());
+    assert_eq!(12, code.len());
+    assert_eq!("briz 0x2a", code[1].to_string());
+    assert_eq!("i32.const 256", code[3].to_string());
+    assert_eq!(Some(16), code[9].target());
+    assert_eq!(44, code[11].next());
+}
+
+#[test]
+fn test_fused_instructions() {
+    let program = [
+        DUP,       // 16
+        MUL,       // 17
+        I32_CONST, // 18
+        3, 0, 0, 0,         // 19 - 22
+        ADD,       // 23
+        DUP,       // 24
+        I32_CONST, // 25
+        20, 0, 0, 0,    // 26 - 29
+        LT_S, // 30
+        BRZI, // 31
+        42, 0, 0, 0,         // 32 - 35
+        I32_CONST, // 36
+        0, 1, 0, 0,        // 37 - 40
+        I32_LOAD, // 41
+        END,      // 42
+    ];
+    let run = |cached: bool, n: i32| {
+        let mut vm = create_vm();
+        vm.write(16, &program);
+        vm.write_i32(77, 0x100);
+        if cached {
+            vm.enable_code_cache(16, program.len());
+        }
+        vm.push_i32(n);
+        let mut ip = 16;
+        vm.run(&mut ip).unwrap();
+        if cached {
+            assert_eq!(4, vm.fused_instructions());
+        }
+        let mut stack = Vec::new();
+        while vm.read_i32(0) != PSTACK as i32 {
+            stack.push(vm.pop_i32());
+        }
+        (ip, stack)
+    };
+    assert_eq!((43, vec![77, 7]), run(true, 2));
+    assert_eq!(run(false, 2), run(true, 2));
+    assert_eq!((43, vec![28]), run(true, 5));
+    assert_eq!(run(false, 5), run(true, 5));
+
+    let program = [
+        EQZ,  // 16
+        BRZI, // 17
+        23, 0, 0, 0,    // 18 - 21
+        ZERO, // 22
+        END,  // 23
+    ];
+    let mut vm = create_vm();
+    vm.write(16, &program);
+    vm.enable_code_cache(16, program.len());
+    vm.push_i32(5);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(1, vm.fused_instructions());
+    assert_eq!(PSTACK as i32, vm.read_i32(0));
+    vm.push_i32(0);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(0, vm.pop_i32());
+    assert_eq!(PSTACK as i32, vm.read_i32(0));
+
+    // nothing is fused once regions are mapped
+    let mut vm = create_vm_with_regions();
+    vm.write(16, &program);
+    vm.enable_code_cache(16, program.len());
+    vm.push_i32(5);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(0, vm.fused_instructions());
+}
+
+#[test]
+fn test_backtrace() {
+    let program = [
+        CALLI, // 16
+        22,
+        0,
+        0,
+        0,   // 17 - 20
+        END, // 21
+        // fn outer
+        CALLI, // 22
+        28,
+        0,
+        0,
+        0,      // 23 - 26
+        RETURN, // 27
+        // fn inner
+        NOP,         // 28
+        UNREACHABLE, // 29
+    ];
+    let symbols = [("main", 16), ("outer", 22), ("inner", 28)];
+    let expected = "#0 0x1d in inner+0x1\n#1 0x1b in outer+0x5\n#2 0x15 in main+0x5\n";
+
+    let mut vm = create_vm();
+    vm.write(16, &program);
+    let mut ip = 16;
+    let err = vm.run(&mut ip).unwrap_err();
+    let at = err.context().unwrap().ip;
+    assert_eq!(29, at);
+    let backtrace = vm.backtrace(at);
+    assert_eq!(vec![29, 27, 21], backtrace.frames);
+    assert_eq!(expected, backtrace.symbolize(&symbols));
+
+    let mut vm = create_vm();
+    vm.enable_private_rstack(4);
+    vm.write(16, &program);
+    let mut ip = 16;
+    let err = vm.run(&mut ip).unwrap_err();
+    assert_eq!(
+        expected,
+        vm.backtrace(err.context().unwrap().ip).symbolize(&symbols)
+    );
+}
+
+#[test]
+fn test_error_context() {
+    let program = [
+        I32_CONST, // 16
+        7, 0, 0, 0,     // 17 - 20
+        CALLI, // 21
+        27, 0, 0, 0,   // 22 - 25
+        END, // 26
+        // fn inner
+        ZERO, // 27
+        0x02, // 28
+    ];
+    let mut vm = create_vm();
+    vm.set_symbols(&[("main", 16), ("inner", 27)]);
+    vm.write(16, &program);
+    let mut ip = 16;
+    let err = vm.run(&mut ip).unwrap_err();
+    assert_eq!(ErrorKind::Trap, err.kind());
+    let context = err.context().unwrap();
+    assert_eq!("???", context.mnemonic());
+    assert_eq!((2, 1), (context.pstack_depth, context.rstack_depth));
+    assert_eq!(
+        "unknown opcode at 0x1c (inner+0x1) executing ??? (0x02), pstack depth 2, rstack depth 1",
+        err.to_string()
+    );
+
+    vm.write(28, &[I32_CONST, 9, 0, 0, 0, CALL_VM]);
+    let mut ip = 16;
+    let err = vm.run(&mut ip).unwrap_err();
+    assert_eq!(ErrorKind::Host, err.kind());
+    assert!(
+        err.to_string()
+            .starts_with("unknown host function 9 at 0x21 (inner+0x6)")
+    );
+
+    let err = vm
+        .add_guard("guard", 0, 8)
+        .and(vm.add_guard("overlap", 4, 8));
+    assert_eq!(ErrorKind::Config, err.unwrap_err().kind());
+}
+
+#[test]
+fn test_throw_catch() {
+    let program = [
+        I32_CONST, // 16
+        1, 0, 0, 0,     // 17 - 20
+        CATCH, // 21
+        37, 0, 0, 0,         // 22 - 25
+        I32_CONST, // 26
+        2, 0, 0, 0,     // 27 - 30
+        CALLI, // 31
+        38, 0, 0, 0,   // 32 - 35
+        END, // 36
+        // handler
+        END, // 37
+        // fn thrower
+        ZERO,      // 38
+        THROW,     // 39
+        I32_CONST, // 40
+        5, 0, 0, 0,     // 41 - 44
+        THROW, // 45
+    ];
+    let mut vm = create_vm();
+    vm.write(16, &program);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(38, ip);
+    assert_eq!(RSTACK as i32, vm.read_i32(4));
+    assert_eq!(5, vm.pop_i32());
+    assert_eq!(1, vm.pop_i32());
+    assert_eq!(PSTACK as i32, vm.read_i32(0));
+
+    let mut vm = create_vm();
+    vm.enable_private_rstack(4);
+    vm.write(16, &program);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(Some(&[][..]), vm.private_rstack());
+    assert_eq!(5, vm.pop_i32());
+
+    // the handler is gone after END_CATCH
+    let program = [
+        CATCH, // 16
+        28, 0, 0, 0,         // 17 - 20
+        END_CATCH, // 21
+        I32_CONST, // 22
+        9, 0, 0, 0,     // 23 - 26
+        THROW, // 27
+        END,   // 28
+    ];
+    let mut vm = create_vm();
+    vm.write(16, &program);
+    let mut ip = 16;
+    let err = vm.run(&mut ip).unwrap_err();
+    assert!(matches!(
+        err,
+        VmError::Trap {
+            trap: Trap::Uncaught { code: 9 },
+            ..
+        }
+    ));
+    assert!(err.to_string().starts_with("uncaught throw of 9 at 0x1b"));
+}
+
+#[test]
+fn test_throw_catch_c() {
+    aot_c::check_program("throws");
+}
+
+#[test]
+fn test_catch_scope() {
+    let uncaught = |vm: &mut VM, ip: usize| {
+        let mut ip = ip;
+        let err = vm.run(&mut ip).unwrap_err();
+        matches!(
+            err,
+            VmError::Trap {
+                trap: Trap::Uncaught { .. },
+                ..
+            }
+        )
+    };
+
+    // the handlers installed by a function go with it
+    let program = [
+        CALLI, // 16
+        28, 0, 0, 0,         // 17 - 20
+        I32_CONST, // 21
+        7, 0, 0, 0,     // 22 - 25
+        THROW, // 26
+        END,   // 27
+        // fn
+        CATCH, // 28
+        39, 0, 0, 0,          // 29 - 32
+        TAIL_CALLI, // 33
+        38, 0, 0, 0,      // 34 - 37
+        RETURN, // 38
+        // handler
+        END, // 39
+    ];
+    let mut returns = program;
+    returns[33 - 16] = RETURN;
+    aot_c::check_program("scope");
+    for program in [program, returns] {
+        for (private, cached) in [(false, false), (true, false), (false, true)] {
+            let mut vm = create_vm();
+            vm.write(16, &program);
+            if private {
+                vm.enable_private_rstack(4);
+            }
+            if cached {
+                vm.enable_code_cache(16, program.len());
+            }
+            assert!(uncaught(&mut vm, 16));
+        }
+    }
+
+    // and all of them with an error leaving `run` or `step`
+    let program = [
+        CATCH, // 16
+        29,
+        0,
+        0,
+        0,           // 17 - 20
+        UNREACHABLE, // 21
+        I32_CONST,   // 22
+        3,
+        0,
+        0,
+        0,     // 23 - 26
+        THROW, // 27
+        END,   // 28
+        // handler
+        END, // 29
+    ];
+    let mut vm = create_vm();
+    vm.write(16, &program);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap_err();
+    assert!(uncaught(&mut vm, 22));
+
+    let mut ip = 16;
+    vm.step(&mut ip).unwrap();
+    vm.step(&mut ip).unwrap_err();
+    assert!(uncaught(&mut vm, 22));
+
+    // or ending at `END`
+    let program = [
+        CATCH, // 16
+        22, 0, 0, 0,   // 17 - 20
+        END, // 21
+        // handler
+        END,       // 22
+        I32_CONST, // 23
+        3, 0, 0, 0,     // 24 - 27
+        THROW, // 28
+        END,   // 29
+    ];
+    let mut vm = create_vm();
+    vm.write(16, &program);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert!(uncaught(&mut vm, 23));
+
+    let mut ip = 16;
+    while vm.step(&mut ip).unwrap() {}
+    assert!(uncaught(&mut vm, 23));
+}
+
+#[test]
+fn test_tail_call_br_table() {
+    let program = [
+        I32_CONST, // 16
+        0xe8, 0x03, 0, 0,     // 17 - 20
+        CALLI, // 21
+        27, 0, 0, 0,   // 22 - 25
+        END, // 26
+        // fn countdown
+        DUP,  // 27
+        BRZI, // 28
+        39, 0, 0, 0,          // 29 - 32
+        DEC,        // 33
+        TAIL_CALLI, // 34
+        27, 0, 0, 0,      // 35 - 38
+        RETURN, // 39
+    ];
+    let mut vm = create_vm();
+    // room for the call from main only
+    vm.enable_private_rstack(1);
+    vm.write(16, &program);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(0, vm.pop_i32());
+    assert_eq!(PSTACK as i32, vm.read_i32(0));
+
+    let program = [
+        BR_TABLE, // 16
+        2, 0, 0, 0, // 17 - 20
+        33, 0, 0, 0, // 21 - 24
+        39, 0, 0, 0, // 25 - 28
+        45, 0, 0, 0,         // 29 - 32, default
+        I32_CONST, // 33
+        10, 0, 0, 0,         // 34 - 37
+        END,       // 38
+        I32_CONST, // 39
+        11, 0, 0, 0,         // 40 - 43
+        END,       // 44
+        I32_CONST, // 45
+        12, 0, 0, 0,   // 46 - 49
+        END, // 50
+    ];
+    let mut vm = create_vm();
+    vm.write(16, &program);
+    for (index, expected) in [(0, 10), (1, 11), (2, 12), (-1, 12)] {
+        vm.push_i32(index);
+        let mut ip = 16;
+        vm.run(&mut ip).unwrap();
+        assert_eq!(expected, vm.pop_i32());
+    }
+    // a table reaching past the end of memory
+    let program = [
+        I32_CONST, 0xff, 0xff, 0xff, 0xff, BR_TABLE, 0xff, 0xff, 0xff, 0x7f,
+    ];
+    let mut vm = create_vm();
+    vm.write(16, &program);
+    assert!(decode_range(vm.memory_ref(), 21, 26).is_empty());
+    let mut ip = 16;
+    let err = vm.run(&mut ip).unwrap_err();
+    assert!(matches!(
+        err,
+        VmError::Trap {
+            // the default entry, at 26 + 4 * 0x7fff_ffff
+            trap: Trap::JumpOutOfRange {
+                target: 0x2_0000_0016
+            },
+            ..
+        }
+    ));
+    assert_eq!(22, ip);
+}
+
+#[test]
+fn test_tail_call_br_table_c() {
+    aot_c::check_program("table");
+}
+
+#[test]
+fn test_relative_jumps() {
+    // sums n..1 into the cell at 0x100
+    let program = [
+        I32_CONST, // 16
+        5, 0, 0, 0,   // 17 - 20
+        DUP, // 21
+        JZI, // 22
+        24, 0, 0, 0,         // 23 - 26, to 47
+        DUP,       // 27
+        I32_CONST, // 28
+        0, 1, 0, 0,         // 29 - 32
+        I32_LOAD,  // 33
+        ADD,       // 34
+        I32_CONST, // 35
+        0, 1, 0, 0,         // 36 - 39
+        I32_STORE, // 40
+        DEC,       // 41
+        JMPI,      // 42
+        0xea, 0xff, 0xff, 0xff, // 43 - 46, back to 21
+        END,  // 47
+    ];
+    for cached in [false, true] {
+        let mut vm = create_vm();
+        if cached {
+            vm.enable_code_cache(16, program.len());
+        }
+        vm.write(16, &program);
+        let mut ip = 16;
+        vm.run(&mut ip).unwrap();
+        assert_eq!(15, vm.read_i32(0x100));
+        assert_eq!(0, vm.pop_i32());
+
+        let before_memory = [JMPI, 0x9c, 0xff, 0xff, 0xff];
+        vm.write(16, &before_memory);
+        let mut ip = 16;
+        let err = vm.run(&mut ip).unwrap_err();
+        assert!(matches!(
+            err,
+            VmError::Trap {
+                trap: Trap::JumpOutOfRange { target: -83 },
+                ..
+            }
+        ));
+        assert_eq!(17, ip);
+    }
+
+    let past_memory = [I32_CONST, 0, 0x40, 0, 0, JMP];
+    let mut vm = create_vm();
+    vm.write(16, &past_memory);
+    let mut ip = 16;
+    let err = vm.run(&mut ip).unwrap_err();
+    assert_eq!(
+        "jump to 16406 outside memory at 0x15 executing jmp (0x14), pstack depth 0, rstack depth 0",
+        err.to_string()
+    );
+}
+
+#[test]
+fn test_relative_jumps_c() {
+    aot_c::check_program("jump_out");
+}
+
+#[test]
+fn test_locals() {
+    let program = [
+        I32_CONST, // 16
+        3, 0, 0, 0,     // 17 - 20
+        CALLI, // 21
+        27, 0, 0, 0,   // 22 - 25
+        END, // 26
+        // fn f
+        ENTER, // 27
+        2, 0, 0, 0,         // 28 - 31
+        LOCAL_SET, // 32
+        1, 0, 0, 0,         // 33 - 36
+        I32_CONST, // 37
+        7, 0, 0, 0,         // 38 - 41
+        LOCAL_TEE, // 42
+        0, 0, 0, 0,     // 43 - 46
+        CALLI, // 47
+        60, 0, 0, 0,         // 48 - 51
+        LOCAL_GET, // 52
+        1, 0, 0, 0,      // 53 - 56
+        ADD,    // 57
+        LEAVE,  // 58
+        RETURN, // 59
+        // fn g
+        ENTER, // 60
+        1, 0, 0, 0,         // 61 - 64
+        LOCAL_SET, // 65
+        0, 0, 0, 0,         // 66 - 69
+        LOCAL_GET, // 70
+        0, 0, 0, 0,         // 71 - 74
+        LOCAL_GET, // 75
+        0, 0, 0, 0,      // 76 - 79
+        ADD,    // 80
+        NOP,    // 81
+        LEAVE,  // 82
+        RETURN, // 83
+    ];
+    let mut vm = create_vm();
+    vm.write(16, &program);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(17, vm.pop_i32());
+    assert!(vm.frames().is_empty());
+
+    // debuggers see the frames while the code runs, none are left after an error
+    for private in [false, true] {
+        let mut vm = create_vm();
+        if private {
+            vm.enable_private_rstack(4);
+        }
+        vm.add_unknown_op_handler(&|vm, ip, _| {
+            let frames = vm.frames();
+            assert_eq!(
+                vec![60, 27],
+                frames.iter().map(|f| f.enter).collect::<Vec<_>>()
+            );
+            assert_eq!(
+                (vec![7], 2),
+                (frames[0].locals.clone(), frames[0].rstack_depth)
+            );
+            let symbols = [("main", 16), ("f", 27), ("g", 60)];
+            let expected = "#0 0x51 in g+0x15, locals [7]\n#1 0x34 in f+0x19, locals [7, 3]\n#2 0x1a in main+0xa\n";
+            assert_eq!(expected, vm.backtrace(*ip - 1).symbolize(&symbols));
+            true
+        });
+        vm.write(16, &program);
+        vm.write_u8(0x02, 81);
+        let mut ip = 16;
+        vm.run(&mut ip).unwrap();
+        assert_eq!(17, vm.pop_i32());
+
+        vm.write_u8(UNREACHABLE, 81);
+        let mut ip = 16;
+        vm.run(&mut ip).unwrap_err();
+        assert!(vm.frames().is_empty());
+    }
+
+    // `THROW` drops the frames set up since the `CATCH`
+    let mut vm = create_vm();
+    vm.write(
+        16,
+        &[
+            CATCH, 32, 0, 0, 0, ENTER, 3, 0, 0, 0, I32_CONST, 4, 0, 0, 0, THROW, END,
+        ],
+    );
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(4, vm.pop_i32());
+    assert!(vm.frames().is_empty());
+
+    let mut vm = create_vm();
+    let mut run = |code: &[u8]| {
+        vm.write(16, code);
+        let mut ip = 16;
+        match vm.run(&mut ip) {
+            Err(VmError::Trap { trap, .. }) => trap,
+            r => panic!("{r:?}"),
+        }
+    };
+    assert_eq!(Trap::NoFrame, run(&[LEAVE]));
+    assert_eq!(Trap::BadLocal { index: 0 }, run(&[LOCAL_GET, 0, 0, 0, 0]));
+    assert_eq!(
+        Trap::BadLocal { index: 2 },
+        run(&[ENTER, 2, 0, 0, 0, ZERO, LOCAL_SET, 2, 0, 0, 0])
+    );
+    // the frame of the failed run is gone
+    assert_eq!(Trap::BadLocal { index: 0 }, run(&[LOCAL_GET, 0, 0, 0, 0]));
+
+    let mut vm = create_vm();
+    vm.set_max_locals(3);
+    vm.write(16, &[ENTER, 4, 0, 0, 0]);
+    let mut ip = 16;
+    let err = vm.run(&mut ip).unwrap_err();
+    assert_eq!(
+        "locals overflow, more than 3 slots at 0x10 executing enter (0x07), pstack depth 0, rstack depth 0",
+        err.to_string()
+    );
+}
+
+#[test]
+fn test_locals_c() {
+    aot_c::check_program("locals");
+}
+
+#[test]
+fn test_globals() {
+    let mut vm = create_vm();
+    assert_eq!(
+        Ok(0),
+        vm.add_global("seed", ValueType::I32, true, 0x1_0000_0007)
+    );
+    assert_eq!(Ok(1), vm.add_global("ticks", ValueType::I64, true, -1));
+    assert_eq!(Ok(2), vm.add_global("version", ValueType::I32, false, 3));
+    let err = vm.add_global("seed", ValueType::I32, true, 0).unwrap_err();
+    assert_eq!(ErrorKind::Config, err.kind());
+    assert_eq!(Some(7), vm.global("seed"));
+
+    let program = [
+        GLOBAL_GET, // 16
+        0,
+        0,
+        0,
+        0,          // 17 - 20
+        GLOBAL_GET, // 21
+        2,
+        0,
+        0,
+        0,          // 22 - 25
+        ADD,        // 26
+        GLOBAL_SET, // 27
+        0,
+        0,
+        0,
+        0,              // 28 - 31
+        I64_GLOBAL_GET, // 32
+        1,
+        0,
+        0,
+        0,              // 33 - 36
+        INC,            // 37
+        I64_GLOBAL_SET, // 38
+        1,
+        0,
+        0,
+        0,   // 39 - 42
+        END, // 43
+    ];
+    vm.write(16, &program);
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(Some(10), vm.global("seed"));
+    // the low word wraps to 0, the high one stays
+    assert_eq!(Some(-1 << 32), vm.global("ticks"));
+
+    vm.set_global("version", 4).unwrap();
+    assert_eq!(Some(4), vm.global("version"));
+    assert_eq!(
+        Err(VmError::UnknownGlobal {
+            name: "nope".to_string()
+        }),
+        vm.set_global("nope", 0)
+    );
+
+    let mut run = |code: &[u8]| {
+        vm.write(16, code);
+        let mut ip = 16;
+        match vm.run(&mut ip) {
+            Err(VmError::Trap { trap, .. }) => trap,
+            r => panic!("{r:?}"),
+        }
+    };
+    assert_eq!(
+        Trap::ImmutableGlobal { index: 2 },
+        run(&[ZERO, GLOBAL_SET, 2, 0, 0, 0])
+    );
+    assert_eq!(Trap::BadGlobal { index: 3 }, run(&[GLOBAL_GET, 3, 0, 0, 0]));
+    assert_eq!(Trap::BadGlobal { index: 1 }, run(&[GLOBAL_GET, 1, 0, 0, 0]));
+}
+
+#[test]
+fn test_globals_c() {
+    aot_c::check_program("globals");
+}
+
+#[test]
+fn test_call_indirect() {
+    let program = [
+        I32_CONST, // 16
+        4,
+        0,
+        0,
+        0,         // 17 - 20
+        I32_CONST, // 21
+        1,
+        0,
+        0,
+        0,             // 22 - 25
+        CALL_INDIRECT, // 26
+        1,
+        0,
+        1,
+        0,   // 27 - 30, ( a -- b )
+        END, // 31
+        // fn inc
+        INC,    // 32
+        RETURN, // 33
+        // fn double
+        DUP,    // 34
+        ADD,    // 35
+        RETURN, // 36
+    ];
+    let unary = Signature {
+        params: 1,
+        results: 1,
+    };
+    assert_eq!(0x10001, unary.encode());
+    assert_eq!(unary, Signature::decode(0x10001));
+
+    let mut vm = create_vm();
+    vm.write(16, &program);
+    assert_eq!(Ok(0), vm.add_table_entry(32, unary));
+    assert_eq!(Ok(1), vm.add_table_entry(32, unary));
+    assert_eq!(Ok(()), vm.set_table_entry(1, 34, unary));
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    assert_eq!(8, vm.pop_i32());
+
+    // an index out of the table, or a function with another signature
+    vm.write_u8(2, 22);
+    let mut ip = 16;
+    let err = vm.run(&mut ip).unwrap_err();
+    assert_eq!(
+        "no function 2 in the table at 0x1a executing call_indirect (0x09), pstack depth 1, rstack depth 0",
+        err.to_string()
+    );
+    vm.pop_i32();
+    let binary = Signature {
+        params: 2,
+        results: 1,
+    };
+    vm.add_table_entry(34, binary).unwrap();
+    let mut ip = 16;
+    let r = vm.run(&mut ip);
+    assert!(matches!(
+        r,
+        Err(VmError::Trap {
+            trap: Trap::SignatureMismatch { index: 2 },
+            ..
+        })
+    ));
+
+    // the host can only replace functions the table has, with entries in memory
+    assert_eq!(
+        Err(VmError::UnknownTableEntry { index: 3 }),
+        vm.set_table_entry(3, 34, unary)
+    );
+    let err = vm.add_table_entry(MEMSIZE, unary).unwrap_err();
+    assert_eq!(VmError::EntryOutOfBounds { entry: MEMSIZE }, err);
+    assert_eq!(ErrorKind::Config, err.kind());
+    assert!(vm.set_table_entry(0, MEMSIZE, unary).is_err());
+    assert_eq!(3, vm.table().len());
+}
+
+#[test]
+fn test_call_indirect_c() {
+    aot_c::check_program("indirect");
+}
+
+// runs `code` on the pstack given, top last, and returns the pstack
+fn eval(stack: &[i32], code: &[u8]) -> Vec<i32> {
+    let mut vm = create_vm();
+    for value in stack {
+        vm.push_i32(*value);
+    }
+    vm.write(16, code);
+    vm.write_u8(END, 16 + code.len());
+    let mut ip = 16;
+    vm.run(&mut ip).unwrap();
+    let mut out = Vec::new();
+    while vm.read_i32(0) != PSTACK as i32 {
+        out.insert(0, vm.pop_i32());
+    }
+    out
+}
+
+#[test]
+fn test_wrapping_arith() {
+    assert_eq!(vec![i32::MIN], eval(&[i32::MAX, 1], &[ADD]));
+    assert_eq!(vec![i32::MAX], eval(&[i32::MIN, 1], &[SUB]));
+    assert_eq!(vec![0], eval(&[0x10000, 0x10000], &[MUL]));
+    assert_eq!(vec![i32::MIN], eval(&[i32::MAX], &[INC]));
+    assert_eq!(vec![i32::MAX], eval(&[i32::MIN], &[DEC]));
+    // shift counts are taken mod 32
+    assert_eq!(vec![2], eval(&[1, 33], &[SHL]));
+    assert_eq!(vec![-4], eval(&[-8, 33], &[SHR_S]));
+    assert_eq!(vec![0x7fff_fffc], eval(&[-8, 33], &[SHR_U]));
+    assert_eq!(vec![i32::MIN], eval(&[1, -1], &[SHL]));
+}
+
+#[test]
+fn test_bit_ops() {
+    assert_eq!(vec![32], eval(&[0], &[CLZ]));
+    assert_eq!(vec![7], eval(&[0x80], &[CTZ]));
+    assert_eq!(vec![3], eval(&[0xb0], &[POPCNT]));
+    assert_eq!(vec![0x78563412], eval(&[0x12345678], &[BSWAP]));
+    assert_eq!(vec![0, 31], eval(&[1, 0], &[I64_CLZ]));
+    assert_eq!(vec![0, 64], eval(&[0, 0], &[I64_CTZ]));
+    assert_eq!(vec![0, 33], eval(&[-1, 1], &[I64_POPCNT]));
+    assert_eq!(
+        vec![0x08070605, 0x04030201],
+        eval(&[0x01020304, 0x05060708], &[I64_BSWAP])
+    );
+
+    let extract = |value, offset, width| eval(&[value, offset, width], &[BITFIELD_EXTRACT])[0];
+    assert_eq!(0x5, extract(0x1234_5678, 12, 4));
+    assert_eq!(0x1234_5678, extract(0x1234_5678, 0, 32));
+    assert_eq!(0, extract(-1, 4, 0));
+    // bits past the top are dropped
+    assert_eq!(0xf, extract(-1, 28, 8));
+    let insert =
+        |value, field, offset, width| eval(&[value, field, offset, width], &[BITFIELD_INSERT])[0];
+    assert_eq!(0x1234_a678, insert(0x1234_5678, 0xfa, 12, 4));
+    assert_eq!(0x5234_5678, insert(0x1234_5678, 0x15, 28, 8));
+    assert_eq!(-1, insert(0, -1, 0, 32));
+}
+
+#[test]
+fn test_bit_ops_c() {
+    aot_c::check_program("bits");
+}
+
+#[test]
+fn test_narrow_loads() {
+    // the bytes ff f0 81 80 at 0x100
+    let load = |op: u8, addr: i32| {
+        let mut code = vec![I32_CONST];
+        code.extend_from_slice(&0x8081_f0ffu32.to_le_bytes());
+        code.extend_from_slice(&[I32_CONST, 0, 1, 0, 0, I32_STORE, I32_CONST]);
+        code.extend_from_slice(&addr.to_le_bytes());
+        code.push(op);
+        eval(&[], &code)
+    };
+    assert_eq!(vec![0xff], load(I32_LOAD_8_U, 0x100));
+    assert_eq!(vec![-1], load(I32_LOAD_8_S, 0x100));
+    assert_eq!(vec![-0x0f01], load(I32_LOAD_16_S, 0x100));
+    assert_eq!(vec![0xf0ff], load(I32_LOAD_16_U, 0x100));
+    assert_eq!(vec![0, 0x81], load(I64_LOAD_8_U, 0x102));
+    assert_eq!(vec![-1, -0x7f], load(I64_LOAD_8_S, 0x102));
+    assert_eq!(vec![0, 0x8081], load(I64_LOAD_16_U, 0x102));
+    assert_eq!(vec![-1, -0x7f7f], load(I64_LOAD_16_S, 0x102));
+    assert_eq!(vec![0, 0x8081_f0ff_u32 as i32], load(I64_LOAD_32_U, 0x100));
+    assert_eq!(vec![-1, 0x8081_f0ff_u32 as i32], load(I64_LOAD_32_S, 0x100));
+
+    assert_eq!(
+        vec![-128, 0x7f],
+        eval(&[0x180, 0x17f], &[EXTEND8_S, SWAP, EXTEND8_S, SWAP])
+    );
+    assert_eq!(vec![-0x8000], eval(&[0x1_8000], &[EXTEND16_S]));
+}
+
+#[test]
+fn test_narrow_loads_c() {
+    aot_c::check_program("loads");
+}
+
+#[test]
+fn test_checked_arith() {
+    let max = i32::MAX;
+    let min = i32::MIN;
+    assert_eq!(vec![max], eval(&[max, 1], &[ADD_SAT_S]));
+    assert_eq!(vec![min], eval(&[min, 1], &[SUB_SAT_S]));
+    assert_eq!(vec![min], eval(&[max, -2], &[MUL_SAT_S]));
+    assert_eq!(vec![-1], eval(&[-2, 5], &[ADD_SAT_U]));
+    assert_eq!(vec![0], eval(&[3, 5], &[SUB_SAT_U]));
+    assert_eq!(vec![-1], eval(&[0x10000, 0x10000], &[MUL_SAT_U]));
+    assert_eq!(vec![12], eval(&[3, 4], &[MUL_SAT_U]));
+
+    assert_eq!(vec![min, TRUE], eval(&[max, 1], &[ADD_OVF_S]));
+    assert_eq!(vec![0, TRUE], eval(&[-1, 1], &[ADD_OVF_U]));
+    assert_eq!(vec![-1, FALSE], eval(&[-2, 1], &[ADD_OVF_U]));
+    assert_eq!(vec![max, TRUE], eval(&[min, 1], &[SUB_OVF_S]));
+    assert_eq!(vec![-2, TRUE], eval(&[3, 5], &[SUB_OVF_U]));
+    assert_eq!(vec![0, TRUE], eval(&[0x10000, 0x10000], &[MUL_OVF_S]));
+    assert_eq!(vec![-6, FALSE], eval(&[2, -3], &[MUL_OVF_S]));
+    assert_eq!(vec![-6, TRUE], eval(&[2, -3], &[MUL_OVF_U]));
+
+    assert_eq!(vec![-1], eval(&[2, -3], &[MUL_HI_S]));
+    assert_eq!(vec![1], eval(&[2, -3], &[MUL_HI_U]));
+    assert_eq!(vec![1], eval(&[0x10000, 0x10000], &[MUL_HI_S]));
+
+    assert_eq!(vec![-1, -3], eval(&[-7, 2], &[DIVMOD_S]));
+    assert_eq!(vec![1, 0x7fff_fffc], eval(&[-7, 2], &[DIVMOD_U]));
+    assert_eq!(vec![0, i32::MIN], eval(&[i32::MIN, -1], &[DIVMOD_S]));
+
+    for op in [DIVMOD_S, DIVMOD_U] {
+        let mut vm = create_vm();
+        vm.push_i32(7);
+        vm.push_i32(0);
+        vm.write(16, &[op, END]);
+        let mut ip = 16;
+        let err = vm.run(&mut ip).unwrap_err();
+        assert!(matches!(
+            err,
+            VmError::Trap {
+                trap: Trap::DivisionByZero,
+                ..
+            }
+        ));
+        assert!(err.to_string().starts_with("division by zero at 0x10"));
+    }
+}
+
+#[test]
+fn test_checked_arith_c() {
+    aot_c::check_program("arith");
+}
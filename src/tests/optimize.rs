@@ -0,0 +1,293 @@
+use super::{PSTACK, create_vm};
+use crate::{
+    decode::decode_range,
+    opcode::*,
+    optimize::{OptimizeError, Stats, check, optimize, simplify},
+};
+
+const PROGRAM: [u8; 66] = [
+    I32_CONST, // 16
+    5,
+    0,
+    0,
+    0,         // 17 - 20
+    DUP,       // 21
+    DROP,      // 22
+    I32_CONST, // 23
+    0,
+    0,
+    0,
+    0,         // 24 - 27
+    ADD,       // 28
+    I32_CONST, // 29
+    1,
+    0,
+    0,
+    0,         // 30 - 33
+    ADD,       // 34
+    I32_CONST, // 35
+    7,
+    0,
+    0,
+    0,     // 36 - 39
+    SWAP,  // 40
+    SWAP,  // 41
+    SUB,   // 42
+    CALLI, // 43
+    71,
+    0,
+    0,
+    0,   // 44 - 47
+    BRI, // 48
+    53,
+    0,
+    0,
+    0,    // 49 - 52
+    JMPI, // 53
+    10,
+    0,
+    0,
+    0,           // 54 - 57, to 64
+    UNREACHABLE, // 58
+    NOP,
+    NOP,
+    NOP,
+    NOP,
+    NOP,       // 59 - 63
+    I32_CONST, // 64
+    79,
+    0,
+    0,
+    0,    // 65 - 68
+    CALL, // 69
+    END,  // 70
+    // fn dec
+    I32_CONST, // 71
+    1,
+    0,
+    0,
+    0,      // 72 - 75
+    SUB,    // 76
+    NOP,    // 77
+    RETURN, // 78
+    // fn square
+    DUP,    // 79
+    MUL,    // 80
+    RETURN, // 81
+];
+
+pub(super) fn run(image: &[u8], entry: usize) -> Vec<i32> {
+    let mut vm = create_vm();
+    vm.write(16, image);
+    let mut ip = entry;
+    vm.run(&mut ip).unwrap();
+    let mut stack = Vec::new();
+    while vm.read_i32(0) != PSTACK as i32 {
+        stack.push(vm.pop_i32());
+    }
+    stack
+}
+
+pub(super) fn listing(image: &[u8]) -> Vec<String> {
+    let mut memory = vec![0; 16];
+    memory.extend_from_slice(image);
+    decode_range(&memory, 16, memory.len())
+        .iter()
+        .map(|instr| instr.to_string())
+        .collect()
+}
+
+#[test]
+fn test_optimize() {
+    let mut symbols = [16, 71, 79];
+    let (image, stats) = optimize(&PROGRAM, 16, &mut symbols).unwrap();
+
+    let expected = Stats {
+        bytes_saved: 26,
+        instructions_saved: 14,
+    };
+    assert_eq!(expected, stats);
+    assert_eq!([16, 51, 53], symbols);
+    assert_eq!(Ok(()), check(&image, 16));
+    assert_eq!(run(&PROGRAM, 16), run(&image, symbols[0]));
+    assert_eq!(vec![4], run(&image, symbols[0]));
+
+    let expected = [
+        "i32.const 5",
+        "i32.inc",
+        "i32.const 7",
+        "i32.sub",
+        "calli 0x33",
+        "bri 0x2c",
+        "jmpi 0x2c",
+        "unreachable",
+        "i32.const 53",
+        "call",
+        "end",
+        "i32.dec",
+        "return",
+        "dup",
+        "i32.mul",
+        "return",
+    ];
+    assert_eq!(expected.as_slice(), listing(&image));
+}
+
+#[test]
+fn test_optimize_errors() {
+    // into the immediate of the `I32_CONST`
+    let misaligned = [I32_CONST, 1, 0, 0, 0, BRI, 17, 0, 0, 0];
+    assert_eq!(
+        Err(OptimizeError::MisalignedTarget(21, 17)),
+        optimize(&misaligned, 16, &mut []).map(|(_, stats)| stats)
+    );
+
+    let computed = [I32_CONST, 16, 0, 0, 0, ZERO, BRZ];
+    assert_eq!(Err(OptimizeError::ComputedTarget(22)), check(&computed, 16));
+
+    let truncated = [NOP, I32_CONST, 1];
+    assert_eq!(Err(OptimizeError::Truncated(17)), check(&truncated, 16));
+}
+
+const FOLDABLE: [u8; 58] = [
+    I32_CONST, // 16
+    2,
+    0,
+    0,
+    0,         // 17 - 20
+    I32_CONST, // 21
+    3,
+    0,
+    0,
+    0,         // 22 - 25
+    MUL,       // 26
+    I32_CONST, // 27
+    6,
+    0,
+    0,
+    0,    // 28 - 31
+    EQ,   // 32
+    BRZI, // 33
+    70,
+    0,
+    0,
+    0,         // 34 - 37, never taken
+    I32_CONST, // 38
+    10,
+    0,
+    0,
+    0,         // 39 - 42
+    I32_CONST, // 43
+    4,
+    0,
+    0,
+    0,     // 44 - 47
+    SUB,   // 48
+    CALLI, // 49
+    72,
+    0,
+    0,
+    0,    // 50 - 53
+    ZERO, // 54
+    BRZI, // 55
+    61,
+    0,
+    0,
+    0,           // 56 - 59, always taken
+    UNREACHABLE, // 60
+    I32_CONST,   // 61
+    7,
+    0,
+    0,
+    0,           // 62 - 65
+    ADD,         // 66
+    END,         // 67
+    NOP,         // 68
+    NOP,         // 69
+    UNREACHABLE, // 70
+    END,         // 71
+    // fn inc
+    INC,    // 72
+    RETURN, // 73
+];
+
+#[test]
+fn test_simplify() {
+    let mut symbols = [16, 72];
+    let (image, stats) = simplify(&FOLDABLE, 16, &mut symbols).unwrap();
+
+    let expected = Stats {
+        bytes_saved: 34,
+        instructions_saved: 14,
+    };
+    assert_eq!(expected, stats);
+    assert_eq!([16, 38], symbols);
+    assert_eq!(run(&FOLDABLE, 16), run(&image, symbols[0]));
+    assert_eq!(vec![14], run(&image, symbols[0]));
+
+    let expected = [
+        "i32.const 6",
+        "calli 0x26",
+        "bri 0x1f",
+        "i32.const 7",
+        "i32.add",
+        "end",
+        "i32.inc",
+        "return",
+    ];
+    assert_eq!(expected.as_slice(), listing(&image));
+}
+
+#[test]
+fn test_simplify_folds_to_longer_code() {
+    let (image, stats) = simplify(&[ZERO, INC, END], 16, &mut [16]).unwrap();
+    assert_eq!(-3, stats.bytes_saved);
+    assert_eq!(1, stats.instructions_saved);
+    assert_eq!(vec![1], run(&image, 16));
+
+    let (image, stats) = simplify(&[ZERO, INC, DEC, END], 16, &mut [16]).unwrap();
+    assert_eq!(2, stats.bytes_saved);
+    assert_eq!(["i32.zero", "end"].as_slice(), listing(&image));
+}
+
+#[test]
+fn test_br_table() {
+    let program = [
+        I32_CONST, // 16
+        1, 0, 0, 0,        // 17 - 20
+        NOP,      // 21
+        BR_TABLE, // 22
+        1, 0, 0, 0, // 23 - 26
+        36, 0, 0, 0, // 27 - 30
+        43, 0, 0, 0,         // 31 - 34, default
+        NOP,       // 35
+        I32_CONST, // 36
+        10, 0, 0, 0,         // 37 - 40
+        END,       // 41
+        NOP,       // 42
+        I32_CONST, // 43
+        11, 0, 0, 0,   // 44 - 47
+        END, // 48
+    ];
+
+    let (image, stats) = optimize(&program, 16, &mut []).unwrap();
+    assert_eq!(3, stats.bytes_saved);
+    assert_eq!(Ok(()), check(&image, 16));
+    assert_eq!(vec![11], run(&image, 16));
+    let expected = [
+        "i32.const 1",
+        "br_table 1",
+        "i32.const 10",
+        "end",
+        "i32.const 11",
+        "end",
+    ];
+    assert_eq!(expected.as_slice(), listing(&image));
+
+    let (image, _) = simplify(&image, 16, &mut [16]).unwrap();
+    assert_eq!(vec![11], run(&image, 16));
+    assert_eq!(
+        ["bri 0x15", "i32.const 11", "end"].as_slice(),
+        listing(&image)
+    );
+}
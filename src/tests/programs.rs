@@ -0,0 +1,611 @@
+//! guest programs for the translation tests, shared with the `aot-tests` crate,
+//! which includes this file from its build script and tests. every program is
+//! loaded at 16 into a vm set up as in `create_vm`
+
+use toyvm::{Permissions, Signature, VM, ValueType, opcode::*};
+
+pub struct Program {
+    /// the module the build script generates
+    pub name: &'static str,
+    pub code: &'static [u8],
+    /// `(name, entry, args)` of the functions translated, run with the args pushed
+    pub cases: &'static [(&'static str, usize, &'static [i32])],
+    /// declares the globals and the function table the program uses
+    pub setup: fn(&mut VM),
+}
+
+fn no_setup(_: &mut VM) {}
+
+fn declare_globals(vm: &mut VM) {
+    vm.add_global("counter", ValueType::I32, true, 5).unwrap();
+    vm.add_global("big", ValueType::I64, false, 0x1_0000_0002)
+        .unwrap();
+    vm.add_global("limit", ValueType::I32, false, 9).unwrap();
+}
+
+fn declare_table(vm: &mut VM) {
+    let unary = Signature {
+        params: 1,
+        results: 1,
+    };
+    vm.add_table_entry(34, unary).unwrap();
+    vm.add_table_entry(37, unary).unwrap();
+}
+
+// code at 16 - 31, data from 32 on
+fn map_regions(vm: &mut VM) {
+    vm.add_region("cells", 0, 16, Permissions::RW).unwrap();
+    vm.add_region("code", 16, 16, Permissions::RX).unwrap();
+    let len = vm.memory_ref().len() - 32;
+    vm.add_region("data", 32, len, Permissions::RW).unwrap();
+}
+
+// the translator itself: loops, calls, host functions, traps and inlined ops
+const BASICS: [u8; 130] = [
+    // sum
+    DUP,  // 16
+    BRZI, // 17
+    42,
+    0,
+    0,
+    0,         // 18 - 21
+    DUP,       // 22
+    I32_CONST, // 23
+    0,
+    1,
+    0,
+    0,         // 24 - 27
+    I32_LOAD,  // 28
+    ADD,       // 29
+    I32_CONST, // 30
+    0,
+    1,
+    0,
+    0,         // 31 - 34
+    I32_STORE, // 35
+    DEC,       // 36
+    BRI,       // 37
+    16,
+    0,
+    0,
+    0,    // 38 - 41
+    DROP, // 42
+    END,  // 43
+    // calls
+    I32_CONST, // 44
+    60,
+    0,
+    0,
+    0,         // 45 - 48
+    CALL,      // 49
+    I32_CONST, // 50
+    0,
+    0,
+    0,
+    0,       // 51 - 54
+    CALL_VM, // 55
+    END,     // 56
+    // fn square
+    DUP,    // 57
+    MUL,    // 58
+    RETURN, // 59
+    // fn quad
+    CALLI, // 60
+    57,
+    0,
+    0,
+    0,     // 61 - 64
+    CALLI, // 65
+    57,
+    0,
+    0,
+    0,      // 66 - 69
+    RETURN, // 70
+    // trap
+    ZERO,        // 71
+    UNREACHABLE, // 72
+    // bad_fn
+    I32_CONST, // 73
+    9,
+    0,
+    0,
+    0,       // 74 - 77
+    CALL_VM, // 78
+    END,     // 79
+    // unknown
+    0x02, // 80
+    // mix
+    I32_CONST, // 81
+    0xf9,
+    0xff,
+    0xff,
+    0xff,      // 82 - 85
+    I32_CONST, // 86
+    3,
+    0,
+    0,
+    0,         // 87 - 90
+    DIV_S,     // 91
+    I32_CONST, // 92
+    0xd4,
+    0xfe,
+    0xff,
+    0xff,      // 93 - 96
+    I32_CONST, // 97
+    0,
+    1,
+    0,
+    0,            // 98 - 101
+    I32_STORE_16, // 102
+    I32_CONST,    // 103
+    0,
+    1,
+    0,
+    0,           // 104 - 107
+    I32_LOAD_16, // 108
+    I32_CONST,   // 109
+    1,
+    1,
+    0,
+    0,          // 110 - 113
+    I32_LOAD_8, // 114
+    ADD,        // 115
+    I32_CONST,  // 116
+    3,
+    0,
+    0,
+    0,         // 117 - 120
+    ROTR,      // 121
+    I32_CONST, // 122
+    5,
+    0,
+    0,
+    0,    // 123 - 126
+    GT_U, // 127
+    END,  // 128
+    // jumps
+    I32_CONST, // 129
+    0,
+    0,
+    0,
+    0,   // 130 - 133
+    JZI, // 134
+    10,
+    0,
+    0,
+    0,         // 135 - 138
+    I32_CONST, // 139
+    1,
+    0,
+    0,
+    0,           // 140 - 143
+    UNREACHABLE, // 144
+    END,         // 145
+];
+
+// sums n..1 into the cell at 0x100, the `sum` function of `BASICS`
+pub const SUM_LOOP: &[u8] = BASICS.split_at(28).0;
+
+// CATCH and THROW
+const THROWS: [u8; 38] = [
+    I32_CONST, // 16
+    1, 0, 0, 0,     // 17 - 20
+    CATCH, // 21
+    37, 0, 0, 0,         // 22 - 25
+    I32_CONST, // 26
+    2, 0, 0, 0,     // 27 - 30
+    CALLI, // 31
+    38, 0, 0, 0,   // 32 - 35
+    END, // 36
+    // handler
+    END, // 37
+    // fn thrower
+    ZERO,      // 38
+    THROW,     // 39
+    I32_CONST, // 40
+    5, 0, 0, 0,     // 41 - 44
+    THROW, // 45
+    END,   // 46
+    // uncaught
+    I32_CONST, // 47
+    9, 0, 0, 0,     // 48 - 51
+    THROW, // 52
+    END,   // 53
+];
+
+// handlers going with the function installing them
+const SCOPE: [u8; 43] = [
+    CALLI, // 16
+    28, 0, 0, 0,         // 17 - 20
+    I32_CONST, // 21
+    7, 0, 0, 0,     // 22 - 25
+    THROW, // 26
+    END,   // 27
+    // fn tail calling
+    CATCH, // 28
+    39, 0, 0, 0,          // 29 - 32
+    TAIL_CALLI, // 33
+    38, 0, 0, 0,      // 34 - 37
+    RETURN, // 38
+    // handler
+    END, // 39
+    // return
+    CALLI, // 40
+    52, 0, 0, 0,         // 41 - 44
+    I32_CONST, // 45
+    7, 0, 0, 0,     // 46 - 49
+    THROW, // 50
+    END,   // 51
+    // fn returning
+    CATCH, // 52
+    58, 0, 0, 0,      // 53 - 56
+    RETURN, // 57
+    // handler
+    END, // 58
+];
+
+// BR_TABLE and tail calls
+const TABLE: [u8; 33] = [
+    CALLI, // 16
+    22, 0, 0, 0,   // 17 - 20
+    END, // 21
+    // fn dispatch
+    BR_TABLE, // 22
+    1, 0, 0, 0, // 23 - 26
+    35, 0, 0, 0, // 27 - 30
+    40, 0, 0, 0,          // 31 - 34, default
+    TAIL_CALLI, // 35
+    46, 0, 0, 0,         // 36 - 39
+    I32_CONST, // 40
+    0, 0, 0, 0,      // 41 - 44
+    RETURN, // 45
+    // fn square
+    DUP,    // 46
+    MUL,    // 47
+    RETURN, // 48
+];
+
+// a relative jump out of memory
+const JUMP_OUT: [u8; 5] = [
+    JMPI, // 16
+    0x9c, 0xff, 0xff, 0xff, // 17 - 20, to -83
+];
+
+// frames and locals
+const LOCALS: [u8; 62] = [
+    ENTER, // 16
+    2, 0, 0, 0,         // 17 - 20
+    LOCAL_SET, // 21
+    0, 0, 0, 0,         // 22 - 25
+    LOCAL_GET, // 26
+    0, 0, 0, 0,         // 27 - 30
+    LOCAL_GET, // 31
+    0, 0, 0, 0,         // 32 - 35
+    MUL,       // 36
+    LOCAL_TEE, // 37
+    1, 0, 0, 0,         // 38 - 41
+    LOCAL_GET, // 42
+    0, 0, 0, 0,     // 43 - 46
+    ADD,   // 47
+    LEAVE, // 48
+    END,   // 49
+    // bad_local
+    ENTER, // 50
+    1, 0, 0, 0,         // 51 - 54
+    LOCAL_GET, // 55
+    1, 0, 0, 0,     // 56 - 59
+    LEAVE, // 60
+    END,   // 61
+    // overflow, the locals limit is 0x10000 slots
+    ENTER, // 62
+    0, 0x80, 0, 0,     // 63 - 66
+    ENTER, // 67
+    0, 0x80, 0, 0,     // 68 - 71
+    ENTER, // 72
+    1, 0, 0, 0,   // 73 - 76
+    END, // 77
+];
+
+// globals
+const GLOBALS: [u8; 33] = [
+    GLOBAL_GET, // 16
+    0,
+    0,
+    0,
+    0,          // 17 - 20
+    INC,        // 21
+    GLOBAL_SET, // 22
+    0,
+    0,
+    0,
+    0,          // 23 - 26
+    GLOBAL_GET, // 27
+    0,
+    0,
+    0,
+    0,              // 28 - 31
+    I64_GLOBAL_GET, // 32
+    1,
+    0,
+    0,
+    0,   // 33 - 36
+    END, // 37
+    // immutable
+    I32_CONST, // 38
+    1,
+    0,
+    0,
+    0,          // 39 - 42
+    GLOBAL_SET, // 43
+    2,
+    0,
+    0,
+    0,   // 44 - 47
+    END, // 48
+];
+
+// CALL_INDIRECT
+const INDIRECT: [u8; 32] = [
+    I32_CONST, // 16
+    1,
+    0,
+    0,
+    0,             // 17 - 20
+    CALL_INDIRECT, // 21
+    1,
+    0,
+    1,
+    0,   // 22 - 25, ( a -- b )
+    END, // 26
+    // bad_signature
+    ZERO,          // 27
+    CALL_INDIRECT, // 28
+    2,
+    0,
+    1,
+    0,   // 29 - 32, ( a b -- c )
+    END, // 33
+    // fn square
+    DUP,    // 34
+    MUL,    // 35
+    RETURN, // 36
+    // fn quad
+    CALLI, // 37
+    34,
+    0,
+    0,
+    0,     // 38 - 41
+    CALLI, // 42
+    34,
+    0,
+    0,
+    0,      // 43 - 46
+    RETURN, // 47
+];
+
+// bit counting and bitfields
+const BITS: [u8; 50] = [
+    I32_CONST, // 16
+    0,
+    0,
+    0xf0,
+    0,         // 17 - 20
+    CLZ,       // 21
+    I32_CONST, // 22
+    0x78,
+    0x56,
+    0x34,
+    0x12,      // 23 - 26
+    BSWAP,     // 27
+    I32_CONST, // 28
+    8,
+    0,
+    0,
+    0,         // 29 - 32
+    I32_CONST, // 33
+    12,
+    0,
+    0,
+    0,                // 34 - 37
+    BITFIELD_EXTRACT, // 38
+    I32_CONST,        // 39
+    0xab,
+    0,
+    0,
+    0,         // 40 - 43
+    I32_CONST, // 44
+    28,
+    0,
+    0,
+    0,         // 45 - 48
+    I32_CONST, // 49
+    8,
+    0,
+    0,
+    0,               // 50 - 53
+    BITFIELD_INSERT, // 54
+    I32_CONST,       // 55
+    1,
+    0,
+    0,
+    0,          // 56 - 59
+    ZERO,       // 60
+    I64_CTZ,    // 61
+    I64_POPCNT, // 62
+    I64_BSWAP,  // 63
+    I64_CLZ,    // 64
+    END,        // 65
+];
+
+// narrow loads
+const LOADS: [u8; 38] = [
+    I32_CONST, // 16
+    0xff,
+    0xf0,
+    0x81,
+    0x80,      // 17 - 20
+    I32_CONST, // 21
+    0,
+    1,
+    0,
+    0,         // 22 - 25
+    I32_STORE, // 26
+    I32_CONST, // 27
+    0,
+    1,
+    0,
+    0,            // 28 - 31
+    I32_LOAD_8_S, // 32
+    I32_CONST,    // 33
+    0,
+    1,
+    0,
+    0,             // 34 - 37
+    I32_LOAD_16_U, // 38
+    I32_CONST,     // 39
+    2,
+    1,
+    0,
+    0,             // 40 - 43
+    I64_LOAD_16_S, // 44
+    I32_CONST,     // 45
+    0,
+    1,
+    0,
+    0,           // 46 - 49
+    I64_LOAD_32, // 50
+    EXTEND16_S,  // 51
+    EXTEND8_S,   // 52
+    END,         // 53
+];
+
+// saturating, overflow-flag and high-word arithmetic
+const ARITH: [u8; 43] = [
+    I32_CONST, // 16
+    0xff, 0xff, 0xff, 0x7f,      // 17 - 20
+    I32_CONST, // 21
+    1, 0, 0, 0,         // 22 - 25
+    ADD_SAT_S, // 26
+    I32_CONST, // 27
+    3, 0, 0, 0,         // 28 - 31
+    MUL_OVF_S, // 32
+    MUL_HI_U,  // 33
+    I32_CONST, // 34
+    0xf9, 0xff, 0xff, 0xff,      // 35 - 38
+    I32_CONST, // 39
+    2, 0, 0, 0,         // 40 - 43
+    DIVMOD_S,  // 44
+    SUB_SAT_U, // 45
+    I32_CONST, // 46
+    0xff, 0xff, 0xff, 0xff,      // 47 - 50
+    MUL_HI_S,  // 51
+    I32_CONST, // 52
+    1, 0, 0, 0,         // 53 - 56
+    ADD_OVF_U, // 57
+    END,       // 58
+];
+
+// instructions in memory that is not executable
+const REGIONS: [u8; 19] = [
+    BRI, // 16
+    33, 0, 0, 0,   // 17 - 20, into data
+    END, // 21
+    NOP, // 22
+    NOP, // 23
+    NOP, // 24
+    NOP, // 25
+    NOP, // 26
+    NOP, // 27
+    // straddle
+    I32_CONST, // 28
+    7, 0, 0, 0,    // 29 - 32
+    ZERO, // 33
+    END,  // 34
+];
+
+pub const PROGRAMS: [Program; 12] = [
+    Program {
+        name: "basics",
+        code: &BASICS,
+        cases: &[
+            ("sum", 16, &[10]),
+            ("calls", 44, &[3]),
+            ("trap", 71, &[]),
+            ("bad_fn", 73, &[]),
+            ("unknown", 80, &[]),
+            ("mix", 81, &[]),
+            ("jumps", 129, &[]),
+        ],
+        setup: no_setup,
+    },
+    Program {
+        name: "throws",
+        code: &THROWS,
+        cases: &[("throws", 16, &[]), ("uncaught", 47, &[])],
+        setup: no_setup,
+    },
+    Program {
+        name: "scope",
+        code: &SCOPE,
+        cases: &[("tail_call", 16, &[]), ("returns", 40, &[])],
+        setup: no_setup,
+    },
+    Program {
+        name: "table",
+        code: &TABLE,
+        cases: &[("table", 16, &[7, 0]), ("table_default", 16, &[7, 3])],
+        setup: no_setup,
+    },
+    Program {
+        name: "jump_out",
+        code: &JUMP_OUT,
+        cases: &[("jump_out", 16, &[])],
+        setup: no_setup,
+    },
+    Program {
+        name: "locals",
+        code: &LOCALS,
+        cases: &[
+            ("locals", 16, &[5]),
+            ("bad_local", 50, &[]),
+            ("overflow", 62, &[]),
+        ],
+        setup: no_setup,
+    },
+    Program {
+        name: "globals",
+        code: &GLOBALS,
+        cases: &[("globals", 16, &[]), ("immutable", 38, &[])],
+        setup: declare_globals,
+    },
+    Program {
+        name: "indirect",
+        code: &INDIRECT,
+        cases: &[("indirect", 16, &[3]), ("bad_signature", 27, &[3])],
+        setup: declare_table,
+    },
+    Program {
+        name: "bits",
+        code: &BITS,
+        cases: &[("bits", 16, &[])],
+        setup: no_setup,
+    },
+    Program {
+        name: "loads",
+        code: &LOADS,
+        cases: &[("loads", 16, &[])],
+        setup: no_setup,
+    },
+    Program {
+        name: "arith",
+        code: &ARITH,
+        cases: &[("arith", 16, &[])],
+        setup: no_setup,
+    },
+    Program {
+        name: "regions",
+        code: &REGIONS,
+        cases: &[("data", 16, &[]), ("straddle", 28, &[])],
+        setup: map_regions,
+    },
+];
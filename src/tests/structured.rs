@@ -0,0 +1,123 @@
+use super::{
+    create_vm,
+    optimize::{listing, run},
+};
+use crate::{
+    opcode::*,
+    optimize::OptimizeError,
+    structured::{StructureError, lower},
+};
+
+const STRUCTURED: [u8; 56] = [
+    I32_CONST, // 16
+    5,
+    0,
+    0,
+    0,     // 17 - 20
+    BLOCK, // 21
+    LOOP,  // 22
+    DUP,   // 23
+    EQZ,   // 24
+    // leave the block once n is 0
+    BR_IF_DEPTH, // 25
+    1,
+    0,
+    0,
+    0,         // 26 - 29
+    DUP,       // 30
+    I32_CONST, // 31
+    0,
+    1,
+    0,
+    0,         // 32 - 35
+    I32_LOAD,  // 36
+    ADD,       // 37
+    I32_CONST, // 38
+    0,
+    1,
+    0,
+    0,         // 39 - 42
+    I32_STORE, // 43
+    DEC,       // 44
+    BR_DEPTH,  // 45
+    0,
+    0,
+    0,
+    0,         // 46 - 49
+    END_BLOCK, // 50
+    END_BLOCK, // 51
+    IF,        // 52
+    I32_CONST, // 53
+    1,
+    0,
+    0,
+    0,     // 54 - 57
+    ELSE,  // 58
+    CALLI, // 59
+    66,
+    0,
+    0,
+    0,         // 60 - 63
+    END_BLOCK, // 64
+    END,       // 65
+    // fn two
+    I32_CONST, // 66
+    2,
+    0,
+    0,
+    0,      // 67 - 70
+    RETURN, // 71
+];
+
+#[test]
+fn test_lower() {
+    let mut symbols = [16, 66];
+    let image = lower(&STRUCTURED, 16, &mut symbols).unwrap();
+    assert_eq!([16, 70], symbols);
+    assert_eq!(vec![2], run(&image, 16));
+    let mut vm = create_vm();
+    vm.write(16, &image);
+    vm.run(&mut 16).unwrap();
+    assert_eq!(15, vm.read_i32(0x100));
+
+    let expected = [
+        "i32.const 5",
+        "dup",
+        "i32.eqz",
+        "i32.eqz",
+        "jiz 0x31",
+        "dup",
+        "i32.const 256",
+        "i32.load",
+        "i32.add",
+        "i32.const 256",
+        "i32.store",
+        "i32.dec",
+        "jmpi 0x15",
+        "jiz 0x40",
+        "i32.const 1",
+        "jmpi 0x45",
+        "calli 0x46",
+        "end",
+        "i32.const 2",
+        "return",
+    ];
+    assert_eq!(expected.as_slice(), listing(&image));
+
+    let errors: [(&[u8], StructureError); 5] = [
+        (&[BLOCK, LOOP, END_BLOCK], StructureError::Unclosed(16)),
+        (&[END_BLOCK], StructureError::Unmatched(16)),
+        (&[BLOCK, ELSE, END_BLOCK], StructureError::Unmatched(17)),
+        (
+            &[BLOCK, BR_DEPTH, 1, 0, 0, 0, END_BLOCK],
+            StructureError::Depth { addr: 17, depth: 1 },
+        ),
+        (
+            &[I32_CONST, 16, 0, 0, 0, ZERO, BRZ],
+            StructureError::Image(OptimizeError::ComputedTarget(22)),
+        ),
+    ];
+    for (image, expected) in errors {
+        assert_eq!(Err(expected), lower(image, 16, &mut []));
+    }
+}
@@ -1,41 +1,204 @@
-use crate::{opcode, pop_i32, push_i32, read_i16, read_i32, write_i16, write_i32};
+use crate::{
+    Access, Permissions, Region, opcode, pop_i32, push_i32, read_i16, read_i32, write_i16,
+    write_i32,
+};
 use std::mem;
+use threaded::CodeCache;
+
+pub use backtrace::Backtrace;
+pub use error::{Context, ErrorKind, Trap, VmError};
+pub use frames::Frame;
+pub use globals::{Global, ValueType};
+pub use table::{Signature, TableEntry};
+
+mod backtrace;
+mod error;
+mod frames;
+mod globals;
+#[cfg(feature = "jit")]
+mod jit;
+pub mod rt;
+mod table;
+mod threaded;
 
 pub type VmFn = &'static dyn Fn(&'_ mut VM);
 pub type UnknownOpHandler = &'static dyn Fn(&'_ mut VM, &mut usize, u8) -> bool;
 
-#[derive(Debug)]
-pub enum VmError {
-    UnknownOp(u8, usize),
-    Unreachable(usize),
-    UnknownVmFn(usize),
-}
-
 pub type Result<T> = std::result::Result<T, VmError>;
 
 pub const TRUE: i32 = 0x1;
 pub const FALSE: i32 = 0x0;
 
+pub const PAGE_SIZE: usize = 0x1000;
+
+// `a op b` for the i32 comparison opcodes, e.g. 1 10 LT_S is true
+pub(crate) fn compare_i32(op: u8, a: i32, b: i32) -> bool {
+    match op {
+        opcode::EQ => a == b,
+        opcode::NE => a != b,
+        opcode::LT_S => a < b,
+        opcode::LT_U => (a as u32) < (b as u32),
+        opcode::GT_S => a > b,
+        opcode::GT_U => (a as u32) > (b as u32),
+        opcode::LE_S => a <= b,
+        opcode::LE_U => (a as u32) <= (b as u32),
+        opcode::GE_S => a >= b,
+        opcode::GE_U => (a as u32) >= (b as u32),
+        _ => unreachable!(),
+    }
+}
+
+// `a op b` for the saturating and high word multiply opcodes
+pub(crate) fn arith_i32(op: u8, a: i32, b: i32) -> i32 {
+    let (ua, ub) = (a as u32, b as u32);
+    match op {
+        opcode::ADD_SAT_S => a.saturating_add(b),
+        opcode::ADD_SAT_U => ua.saturating_add(ub) as i32,
+        opcode::SUB_SAT_S => a.saturating_sub(b),
+        opcode::SUB_SAT_U => ua.saturating_sub(ub) as i32,
+        opcode::MUL_SAT_S => a.saturating_mul(b),
+        opcode::MUL_SAT_U => ua.saturating_mul(ub) as i32,
+        opcode::MUL_HI_S => ((i64::from(a) * i64::from(b)) >> 32) as i32,
+        opcode::MUL_HI_U => ((u64::from(ua) * u64::from(ub)) >> 32) as i32,
+        _ => unreachable!(),
+    }
+}
+
+// `a op b` wrapped and whether it overflowed, for the `_OVF` opcodes
+fn overflowing_i32(op: u8, a: i32, b: i32) -> (i32, bool) {
+    let (ua, ub) = (a as u32, b as u32);
+    let unsigned = |(r, o): (u32, bool)| (r as i32, o);
+    match op {
+        opcode::ADD_OVF_S => a.overflowing_add(b),
+        opcode::ADD_OVF_U => unsigned(ua.overflowing_add(ub)),
+        opcode::SUB_OVF_S => a.overflowing_sub(b),
+        opcode::SUB_OVF_U => unsigned(ua.overflowing_sub(ub)),
+        opcode::MUL_OVF_S => a.overflowing_mul(b),
+        opcode::MUL_OVF_U => unsigned(ua.overflowing_mul(ub)),
+        _ => unreachable!(),
+    }
+}
+
+// `op a` for the i32 and i64 bit counting opcodes and `BSWAP`
+fn bits(op: u8, a: i64) -> i64 {
+    let a32 = a as i32;
+    match op {
+        opcode::CLZ => i64::from(a32.leading_zeros()),
+        opcode::CTZ => i64::from(a32.trailing_zeros()),
+        opcode::POPCNT => i64::from(a32.count_ones()),
+        opcode::BSWAP => i64::from(a32.swap_bytes()),
+        opcode::I64_CLZ => i64::from(a.leading_zeros()),
+        opcode::I64_CTZ => i64::from(a.trailing_zeros()),
+        opcode::I64_POPCNT => i64::from(a.count_ones()),
+        opcode::I64_BSWAP => a.swap_bytes(),
+        _ => unreachable!(),
+    }
+}
+
+// mask of the bitfield `width` bits wide at `offset`, both as popped by
+// `BITFIELD_EXTRACT` / `BITFIELD_INSERT`. bits past the top of the i32 are dropped
+fn bitfield(offset: i32, width: i32) -> (u32, u32) {
+    let offset = offset as u32 & 31;
+    let width = (width as u32).min(32 - offset);
+    let mask = u32::MAX.checked_shr(32 - width).unwrap_or(0);
+    (offset, mask)
+}
+
+// bytes read by a narrow load and whether they are sign-extended
+fn narrow_load(op: u8) -> (usize, bool) {
+    match op {
+        opcode::I32_LOAD_8 | opcode::I64_LOAD_8 => (1, false),
+        opcode::I32_LOAD_8_S | opcode::I64_LOAD_8_S => (1, true),
+        opcode::I32_LOAD_16_U | opcode::I64_LOAD_16 => (2, false),
+        opcode::I32_LOAD_16 | opcode::I64_LOAD_16_S => (2, true),
+        opcode::I64_LOAD_32 => (4, false),
+        opcode::I64_LOAD_32_S => (4, true),
+        _ => unreachable!(),
+    }
+}
+
+struct CallStack {
+    frames: Vec<i32>,
+    max_depth: usize,
+}
+
+// installed by `CATCH`, the stacks as they were then
+struct CatchFrame {
+    addr: usize,
+    psp: usize,
+    // rstack pointer, or depth of the private rstack
+    rstack: usize,
+    // frames and local slots
+    frames: usize,
+    locals: usize,
+    // rstack depth of the function that installed it
+    depth: usize,
+}
+
 pub struct VM {
     memory: Vec<u8>,
     functions: Vec<VmFn>,
     pstack_top: usize,
     rstack_top: usize,
+    // stack pointers cached while executing, see `load_stack_pointers`
+    psp: usize,
+    rsp: usize,
+    // where the stack pointers were when guest code first ran
+    pstack_base: Option<usize>,
+    rstack_base: Option<usize>,
+    symbols: Vec<(String, usize)>,
+    // instruction being executed by code from `aot::rust`, for error contexts
+    at: usize,
+    max_pages: usize,
+    regions: Vec<Region>,
+    call_stack: Option<CallStack>,
+    handlers: Vec<CatchFrame>,
+    // set up by `ENTER`, the local slots of all of them in `locals`
+    frames: Vec<frames::FrameRecord>,
+    locals: Vec<i32>,
+    max_locals: usize,
+    globals: Vec<Global>,
+    // entry points for `CALL_INDIRECT`
+    table: Vec<TableEntry>,
+    code_cache: Option<CodeCache>,
+    #[cfg(feature = "jit")]
+    jit: Option<jit::Jit>,
     unknown_opcode_handler: Vec<UnknownOpHandler>,
 }
 
 impl VM {
+    /// `memory` is padded with zeros to a whole number of pages of `PAGE_SIZE` bytes
     pub fn new(
-        memory: Vec<u8>,
+        mut memory: Vec<u8>,
         functions: Vec<VmFn>,
         pstack_top: usize,
         rstack_top: usize,
     ) -> Self {
+        memory.resize(memory.len().next_multiple_of(PAGE_SIZE), 0);
+        let max_pages = memory.len() / PAGE_SIZE;
         VM {
             memory,
             functions,
             pstack_top,
             rstack_top,
+            psp: 0,
+            rsp: 0,
+            pstack_base: None,
+            rstack_base: None,
+            symbols: Vec::new(),
+            at: 0,
+            max_pages,
+            regions: Vec::new(),
+            call_stack: None,
+            handlers: Vec::new(),
+            frames: Vec::new(),
+            locals: Vec::new(),
+            max_locals: frames::MAX_LOCALS,
+            globals: Vec::new(),
+            table: Vec::new(),
+            code_cache: None,
+            #[cfg(feature = "jit")]
+            jit: None,
             unknown_opcode_handler: Vec::new(),
         }
     }
@@ -58,22 +221,27 @@ impl VM {
     }
 
     pub fn write_u8(&mut self, value: u8, idx: usize) {
+        self.code_written(idx, 1);
         self.memory[idx] = value;
     }
 
     pub fn write_i16(&mut self, value: i16, idx: usize) {
+        self.code_written(idx, 2);
         write_i16(&mut self.memory[idx..], value);
     }
 
     pub fn write_i32(&mut self, value: i32, idx: usize) {
+        self.code_written(idx, 4);
         write_i32(&mut self.memory[idx..], value);
     }
 
     pub fn write(&mut self, to: usize, src: &[u8]) {
+        self.code_written(to, src.len());
         self.memory[to..to + src.len()].copy_from_slice(src);
     }
 
     pub fn memcopy(&mut self, from: usize, to: usize, n: usize) {
+        self.code_written(to, n);
         self.memory.copy_within(from..from + n, to);
     }
 
@@ -99,10 +267,176 @@ impl VM {
         &self.memory
     }
 
+    /// changes made through this slice are not seen by the code cache,
+    /// call `invalidate_code_cache` after modifying code
     pub fn memory_ref_mut(&mut self) -> &mut [u8] {
         &mut self.memory
     }
 
+    /// decodes the code in `start..start + len` once and lets `run` and `step`
+    /// dispatch it through a handler per instruction, with immediates and branch
+    /// targets resolved. the stack, arithmetic, compare, branch, call and i32
+    /// load/store ops have handlers of their own, the rest go through the same
+    /// code as without the cache. writes into the range through the vm
+    /// (guest stores included) invalidate the cache and it is decoded again
+    /// before the next instruction runs from it. `benches/dispatch.rs` times it
+    pub fn enable_code_cache(&mut self, start: usize, len: usize) {
+        self.code_cache = Some(CodeCache::new(start..start + len));
+    }
+
+    pub fn disable_code_cache(&mut self) {
+        self.code_cache = None;
+    }
+
+    /// number of instruction pairs the code cache executes as a single fused operation
+    pub fn fused_instructions(&self) -> usize {
+        self.code_cache.as_ref().map_or(0, |cache| cache.fused())
+    }
+
+    pub fn invalidate_code_cache(&mut self) {
+        if let Some(cache) = &mut self.code_cache {
+            cache.invalidate();
+        }
+    }
+
+    /// compiles functions to native code once they have been called `threshold` times.
+    /// compiled code only runs from `run` and while no memory regions are mapped.
+    /// on targets other than x86-64 linux nothing is compiled
+    #[cfg(feature = "jit")]
+    pub fn enable_jit(&mut self, threshold: u32) {
+        self.jit = Some(jit::Jit::new(threshold));
+    }
+
+    /// number of functions currently compiled to native code
+    #[cfg(feature = "jit")]
+    pub fn jit_compiled(&self) -> usize {
+        self.jit.as_ref().map_or(0, |jit| jit.compiled())
+    }
+
+    fn code_written(&mut self, addr: usize, len: usize) {
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            jit.code_written(addr, len);
+        }
+        if let Some(cache) = &mut self.code_cache
+            && cache.overlaps(addr, len)
+        {
+            cache.invalidate();
+        }
+    }
+
+    /// memory size in pages of `PAGE_SIZE` bytes
+    pub fn memory_size(&self) -> usize {
+        self.memory.len() / PAGE_SIZE
+    }
+
+    pub fn max_pages(&self) -> usize {
+        self.max_pages
+    }
+
+    /// upper limit for `grow_memory`. defaults to the initial memory size,
+    /// i.e. memory does not grow unless the host allows it
+    pub fn set_max_pages(&mut self, max_pages: usize) {
+        self.max_pages = max_pages;
+    }
+
+    /// appends `delta` zeroed pages to memory.
+    /// returns the previous size in pages or `None` if the new size would exceed `max_pages`
+    pub fn grow_memory(&mut self, delta: usize) -> Option<usize> {
+        let pages = self.memory_size();
+        let new_pages = pages.checked_add(delta)?;
+        if new_pages > self.max_pages {
+            return None;
+        }
+        let new_len = self.memory.len() + delta * PAGE_SIZE;
+        self.memory.resize(new_len, 0);
+        Some(pages)
+    }
+
+    /// maps `len` bytes starting at `start` with the given permissions.
+    /// as long as no region is mapped, all of memory is readable, writable and executable.
+    /// once a region is mapped, guest access to unmapped memory is denied.
+    /// the region has to fit into memory as it is now
+    pub fn add_region(
+        &mut self,
+        name: &str,
+        start: usize,
+        len: usize,
+        permissions: Permissions,
+    ) -> Result<()> {
+        let Some(end) = start
+            .checked_add(len)
+            .filter(|end| *end <= self.memory.len())
+        else {
+            return Err(VmError::RegionOutOfBounds { start, len });
+        };
+        let range = start..end;
+        if self.regions.iter().any(|r| r.overlaps(&range)) {
+            return Err(VmError::RegionOverlap {
+                start: range.start,
+                end: range.end,
+            });
+        }
+        self.regions.push(Region {
+            name: name.to_string(),
+            range,
+            permissions,
+        });
+        self.regions.sort_by_key(|r| r.range.start);
+        self.invalidate_code_cache();
+        Ok(())
+    }
+
+    /// maps a region that can not be accessed at all, e.g. to catch stack overflows
+    pub fn add_guard(&mut self, name: &str, start: usize, len: usize) -> Result<()> {
+        self.add_region(name, start, len, Permissions::NONE)
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn region_at(&self, addr: usize) -> Option<&Region> {
+        self.regions.iter().find(|r| r.contains(addr))
+    }
+
+    // every stack op goes through here, keep the no regions case small enough to inline
+    #[inline]
+    fn check_access(&self, addr: usize, len: usize, access: Access) -> Result<()> {
+        if self.regions.is_empty() {
+            return Ok(());
+        }
+        self.check_regions(addr, len, access)
+    }
+
+    #[inline(never)]
+    fn check_regions(&self, addr: usize, len: usize, access: Access) -> Result<()> {
+        let end = addr + len;
+        let mut a = addr;
+        while a < end {
+            match self.region_at(a) {
+                Some(r) if r.permissions.allows(access) => a = r.range.end,
+                _ => return Err(VmError::trap(Trap::PermissionDenied { addr: a, access })),
+            }
+        }
+        Ok(())
+    }
+
+    fn fetch_i32(&self, ip: usize) -> Result<i32> {
+        self.check_access(ip, 4, Access::Execute)?;
+        Ok(self.read_i32(ip))
+    }
+
+    #[inline]
+    fn load(&self, addr: usize, len: usize) -> Result<()> {
+        self.check_access(addr, len, Access::Read)
+    }
+
+    #[inline]
+    fn store(&self, addr: usize, len: usize) -> Result<()> {
+        self.check_access(addr, len, Access::Write)
+    }
+
     pub fn push_i32(&mut self, value: i32) {
         let stack_top = self.read_i32(self.pstack_top) as usize;
         let stack_top = push_i32(&mut self.memory, stack_top, value);
@@ -116,17 +450,154 @@ impl VM {
         value
     }
 
-    fn rs_push(&mut self, value: i32) {
-        let stack_top = self.read_i32(self.rstack_top) as usize;
-        let stack_top = push_i32(&mut self.memory, stack_top, value);
-        self.write_i32(stack_top as i32, self.rstack_top);
+    // while executing guest code the stack pointers live in `psp` and `rsp`.
+    // the cells in memory are only brought up to date when something
+    // other than the stack ops could observe them: host functions,
+    // unknown op handlers, guest loads and stores of the cells and
+    // returning from `step` or `run`
+    fn load_stack_pointers(&mut self) {
+        self.psp = self.read_i32(self.pstack_top) as usize;
+        self.rsp = self.read_i32(self.rstack_top) as usize;
+        self.pstack_base.get_or_insert(self.psp);
+        self.rstack_base.get_or_insert(self.rsp);
     }
 
-    fn rs_pop(&mut self) -> i32 {
-        let stack_top = self.read_i32(self.rstack_top) as usize;
-        let (stack_top, value) = pop_i32(&self.memory, stack_top);
-        self.write_i32(stack_top as i32, self.rstack_top);
-        value
+    fn store_stack_pointers(&mut self) {
+        let (psp, rsp) = (self.psp, self.rsp);
+        self.write_i32(psp as i32, self.pstack_top);
+        if self.call_stack.is_none() {
+            self.write_i32(rsp as i32, self.rstack_top);
+        }
+    }
+
+    fn touches_stack_pointers(&self, addr: usize, len: usize) -> bool {
+        let touches = |cell: usize| addr < cell + 4 && cell < addr + len;
+        touches(self.pstack_top) || (self.call_stack.is_none() && touches(self.rstack_top))
+    }
+
+    fn guest_load(&mut self, addr: usize, len: usize) -> Result<()> {
+        self.load(addr, len)?;
+        if self.touches_stack_pointers(addr, len) {
+            self.store_stack_pointers();
+        }
+        Ok(())
+    }
+
+    fn guest_store(&mut self, addr: usize, len: usize, f: impl FnOnce(&mut Self)) -> Result<()> {
+        self.store(addr, len)?;
+        if self.touches_stack_pointers(addr, len) {
+            self.store_stack_pointers();
+            f(self);
+            self.load_stack_pointers();
+        } else {
+            f(self);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn ps_push(&mut self, value: i32) -> Result<()> {
+        self.store(self.psp, 4)?;
+        self.psp = push_i32(&mut self.memory, self.psp, value);
+        Ok(())
+    }
+
+    #[inline]
+    fn ps_pop(&mut self) -> Result<i32> {
+        self.load(self.psp + 4, 4)?;
+        let (psp, value) = pop_i32(&self.memory, self.psp);
+        self.psp = psp;
+        Ok(value)
+    }
+
+    // an i64 takes two cells, laid out as in memory: the low word on top
+    fn ps_push_i64(&mut self, value: i64) -> Result<()> {
+        self.ps_push((value >> 32) as i32)?;
+        self.ps_push(value as i32)
+    }
+
+    fn ps_pop_i64(&mut self) -> Result<i64> {
+        let low = self.ps_pop()? as u32;
+        let high = self.ps_pop()?;
+        Ok(i64::from(high) << 32 | i64::from(low))
+    }
+
+    /// keeps return addresses in a stack owned by the vm instead of guest memory,
+    /// out of reach of loads and stores. the rstack cell is no longer used
+    pub fn enable_private_rstack(&mut self, max_depth: usize) {
+        self.call_stack = Some(CallStack {
+            frames: Vec::with_capacity(max_depth),
+            max_depth,
+        });
+    }
+
+    /// contents of the private return stack, bottom first
+    pub fn private_rstack(&self) -> Option<&[i32]> {
+        self.call_stack.as_ref().map(|cs| cs.frames.as_slice())
+    }
+
+    fn rs_push(&mut self, value: i32) -> Result<()> {
+        if let Some(cs) = &mut self.call_stack {
+            if cs.frames.len() >= cs.max_depth {
+                let max_depth = cs.max_depth;
+                return Err(VmError::trap(Trap::CallStackOverflow { max_depth }));
+            }
+            cs.frames.push(value);
+            return Ok(());
+        }
+        self.store(self.rsp, 4)?;
+        self.rsp = push_i32(&mut self.memory, self.rsp, value);
+        Ok(())
+    }
+
+    fn rs_pop(&mut self) -> Result<i32> {
+        if let Some(cs) = &mut self.call_stack {
+            return cs
+                .frames
+                .pop()
+                .ok_or(VmError::trap(Trap::CallStackUnderflow));
+        }
+        self.load(self.rsp + 4, 4)?;
+        let (rsp, value) = pop_i32(&self.memory, self.rsp);
+        self.rsp = rsp;
+        Ok(value)
+    }
+
+    // unwinds to the innermost handler, leaving `code` on the pstack
+    fn throw(&mut self, ip: &mut usize, code: i32) -> Result<()> {
+        let Some(handler) = self.handlers.pop() else {
+            return Err(VmError::trap(Trap::Uncaught { code }));
+        };
+        self.psp = handler.psp;
+        match &mut self.call_stack {
+            Some(cs) => cs.frames.truncate(handler.rstack),
+            None => self.rsp = handler.rstack,
+        }
+        self.frames.truncate(handler.frames);
+        self.locals.truncate(handler.locals);
+        self.ps_push(code)?;
+        *ip = handler.addr;
+        Ok(())
+    }
+
+    // a function returning or tail calling takes the handlers it installed along
+    fn leave_handlers(&mut self) {
+        if self.handlers.is_empty() {
+            return;
+        }
+        let depth = self.rstack_depth(self.rsp);
+        while self.handlers.last().is_some_and(|h| h.depth >= depth) {
+            self.handlers.pop();
+        }
+    }
+
+    // target of a relative jump, `base` being the address after its opcode
+    fn relative_target(&self, base: usize, offset: i32) -> Result<usize> {
+        let target = base as i64 + i64::from(offset);
+        if !(0..self.memory.len() as i64).contains(&target) {
+            return Err(VmError::trap(Trap::JumpOutOfRange { target }));
+        }
+        Ok(target as usize)
     }
 
     pub fn add_function(&mut self, f: VmFn) -> usize {
@@ -139,38 +610,92 @@ impl VM {
     }
 
     fn vm_fn(&mut self) -> Result<()> {
-        let fn_idx = self.pop_i32() as usize;
+        let fn_idx = self.ps_pop()? as usize;
         if fn_idx >= self.functions.len() {
-            return Err(VmError::UnknownVmFn(fn_idx));
+            return Err(VmError::UnknownVmFn {
+                index: fn_idx,
+                context: Box::default(),
+            });
         }
+        self.store_stack_pointers();
         self.functions[fn_idx](self);
+        self.load_stack_pointers();
         Ok(())
     }
 
     pub fn run(&mut self, ip: &mut usize) -> Result<()> {
-        while self.step(ip)? {}
-
-        Ok(())
+        self.load_stack_pointers();
+        let result = loop {
+            let mut at = *ip;
+            // the jit looks for compiled code at every ip, without it cached code
+            // runs in a loop of its own until it leaves the cache
+            let r = match self.cached_instruction(*ip) {
+                Some(_) if cfg!(not(feature = "jit")) => self.run_cached(ip, &mut at),
+                Some(d) => self.dispatch(ip, &d),
+                None => self.exec(ip),
+            };
+            #[cfg(feature = "jit")]
+            if r.is_ok() {
+                self.jit_enter(ip);
+            }
+            match r {
+                Ok(true) => {}
+                Ok(false) => break Ok(()),
+                Err(e) => break Err(self.with_context(e, at)),
+            }
+        };
+        self.end_run();
+        self.store_stack_pointers();
+        result
     }
 
     pub fn step(&mut self, ip: &mut usize) -> Result<bool> {
+        self.load_stack_pointers();
+        let at = *ip;
+        // a fused pair would be two steps
+        let result = match self.cached_instruction(*ip) {
+            Some(d) if !d.fused => self.dispatch(ip, &d),
+            _ => self.exec(ip),
+        };
+        let result = result.map_err(|e| self.with_context(e, at));
+        if !matches!(result, Ok(true)) {
+            self.end_run();
+        }
+        self.store_stack_pointers();
+        result
+    }
+
+    // a run ending, with `END` or an error, takes the `CATCH` handlers
+    // installed by the code and the frames it set up along
+    fn end_run(&mut self) {
+        self.handlers.clear();
+        self.frames.clear();
+        self.locals.clear();
+    }
+
+    fn exec(&mut self, ip: &mut usize) -> Result<bool> {
+        self.check_access(*ip, 1, Access::Execute)?;
         let op = self.memory[*ip];
         *ip += 1;
+        self.execute(op, ip)
+    }
 
+    // `ip` points past the opcode byte
+    fn execute(&mut self, op: u8, ip: &mut usize) -> Result<bool> {
         match op {
             opcode::UNREACHABLE => {
-                return Err(VmError::Unreachable(*ip));
+                return Err(VmError::trap(Trap::Unreachable));
             }
             opcode::NOP => {}
             opcode::END => {
                 return Ok(false);
             }
             opcode::BRI => {
-                *ip = self.read_i32(*ip) as usize;
+                *ip = self.fetch_i32(*ip)? as usize;
             }
             opcode::BRZI => {
-                let is_zero = self.pop_i32() == 0;
-                let addr = self.read_i32(*ip) as usize;
+                let is_zero = self.ps_pop()? == 0;
+                let addr = self.fetch_i32(*ip)? as usize;
                 if is_zero {
                     *ip = addr;
                 } else {
@@ -178,40 +703,42 @@ impl VM {
                 }
             }
             opcode::BR => {
-                *ip = self.pop_i32() as usize;
+                *ip = self.ps_pop()? as usize;
             }
             opcode::BRZ => {
-                let is_zero = self.pop_i32() == 0;
-                let addr = self.pop_i32() as usize;
+                let is_zero = self.ps_pop()? == 0;
+                let addr = self.ps_pop()? as usize;
                 if is_zero {
                     *ip = addr;
                 }
             }
             opcode::JMP => {
-                let offset = self.pop_i32() as usize;
-                *ip += offset;
+                let offset = self.ps_pop()?;
+                *ip = self.relative_target(*ip, offset)?;
             }
             opcode::JZ => {
-                let is_zero = self.pop_i32() == 0;
-                let offset = self.pop_i32() as usize;
+                let is_zero = self.ps_pop()? == 0;
+                let offset = self.ps_pop()?;
                 if is_zero {
-                    *ip += offset;
+                    *ip = self.relative_target(*ip, offset)?;
                 }
             }
             opcode::JMPI => {
-                *ip += self.read_i32(*ip) as usize;
+                let offset = self.fetch_i32(*ip)?;
+                *ip = self.relative_target(*ip, offset)?;
             }
             opcode::JZI => {
-                let is_zero = self.pop_i32() == 0;
-                let offset = self.read_i32(*ip) as usize;
+                let is_zero = self.ps_pop()? == 0;
+                let offset = self.fetch_i32(*ip)?;
                 if is_zero {
-                    *ip += offset;
+                    *ip = self.relative_target(*ip, offset)?;
                 } else {
                     *ip += 4;
                 }
             }
             opcode::RETURN => {
-                *ip = self.rs_pop() as usize;
+                self.leave_handlers();
+                *ip = self.rs_pop()? as usize;
             }
             opcode::CALL_VM => {
                 self.vm_fn()?;
@@ -219,234 +746,409 @@ impl VM {
             opcode::CALL => {
                 // store ip to return stack
                 // ip = pop
-                self.rs_push(*ip as i32);
-                *ip = self.pop_i32() as usize;
+                self.rs_push(*ip as i32)?;
+                *ip = self.ps_pop()? as usize;
+                #[cfg(feature = "jit")]
+                self.jit_note_call(*ip);
             }
             opcode::CALLI => {
-                self.rs_push(*ip as i32 + 4);
-                *ip = self.read_i32(*ip) as usize;
+                self.rs_push(*ip as i32 + 4)?;
+                *ip = self.fetch_i32(*ip)? as usize;
+                #[cfg(feature = "jit")]
+                self.jit_note_call(*ip);
+            }
+            opcode::CALL_INDIRECT => {
+                let signature = Signature::decode(self.fetch_i32(*ip)?);
+                let index = self.ps_pop()? as u32 as usize;
+                let entry = self.indirect_target(index, signature)?;
+                self.rs_push(*ip as i32 + 4)?;
+                *ip = entry;
+                #[cfg(feature = "jit")]
+                self.jit_note_call(*ip);
+            }
+            opcode::TAIL_CALL => {
+                self.leave_handlers();
+                *ip = self.ps_pop()? as usize;
+                #[cfg(feature = "jit")]
+                self.jit_note_call(*ip);
+            }
+            opcode::TAIL_CALLI => {
+                *ip = self.fetch_i32(*ip)? as usize;
+                self.leave_handlers();
+                #[cfg(feature = "jit")]
+                self.jit_note_call(*ip);
+            }
+            opcode::BR_TABLE => {
+                let index = self.ps_pop()? as u32;
+                let n = self.fetch_i32(*ip)? as u32;
+                // the default follows the n targets
+                let slot = *ip as i64 + 4 + 4 * i64::from(index.min(n));
+                if slot + 4 > self.memory.len() as i64 {
+                    return Err(VmError::trap(Trap::JumpOutOfRange { target: slot }));
+                }
+                *ip = self.fetch_i32(slot as usize)? as usize;
+            }
+            opcode::CATCH => {
+                let addr = self.fetch_i32(*ip)? as usize;
+                *ip += 4;
+                let rstack = match &self.call_stack {
+                    Some(cs) => cs.frames.len(),
+                    None => self.rsp,
+                };
+                self.handlers.push(CatchFrame {
+                    addr,
+                    psp: self.psp,
+                    rstack,
+                    frames: self.frames.len(),
+                    locals: self.locals.len(),
+                    depth: self.rstack_depth(self.rsp),
+                });
+            }
+            opcode::END_CATCH => {
+                self.handlers.pop();
+            }
+            opcode::THROW => {
+                // 0 is no exception, as in Forth
+                let code = self.ps_pop()?;
+                if code != 0 {
+                    self.throw(ip, code)?;
+                }
+            }
+            opcode::ENTER => {
+                let n = self.fetch_i32(*ip)? as u32 as usize;
+                self.enter(*ip - 1, n)?;
+                *ip += 4;
+            }
+            opcode::LEAVE => {
+                self.leave()?;
+            }
+            opcode::LOCAL_GET => {
+                let index = self.fetch_i32(*ip)? as u32 as usize;
+                let value = *self.local(index)?;
+                self.ps_push(value)?;
+                *ip += 4;
+            }
+            opcode::LOCAL_SET | opcode::LOCAL_TEE => {
+                let index = self.fetch_i32(*ip)? as u32 as usize;
+                let value = self.ps_pop()?;
+                *self.local(index)? = value;
+                if op == opcode::LOCAL_TEE {
+                    self.ps_push(value)?;
+                }
+                *ip += 4;
+            }
+            opcode::GLOBAL_GET
+            | opcode::GLOBAL_SET
+            | opcode::I64_GLOBAL_GET
+            | opcode::I64_GLOBAL_SET => {
+                let index = self.fetch_i32(*ip)? as u32 as usize;
+                let ty = match op {
+                    opcode::GLOBAL_GET | opcode::GLOBAL_SET => ValueType::I32,
+                    _ => ValueType::I64,
+                };
+                if matches!(op, opcode::GLOBAL_GET | opcode::I64_GLOBAL_GET) {
+                    self.global_get(index, ty)?;
+                } else {
+                    self.global_set(index, ty)?;
+                }
+                *ip += 4;
+            }
+            opcode::EXTEND8_S => {
+                let a = self.ps_pop()?;
+                self.ps_push(a as i8 as i32)?;
+            }
+            opcode::EXTEND16_S => {
+                let a = self.ps_pop()?;
+                self.ps_push(a as i16 as i32)?;
+            }
+            opcode::CLZ | opcode::CTZ | opcode::POPCNT | opcode::BSWAP => {
+                let a = self.ps_pop()?;
+                self.ps_push(bits(op, a.into()) as i32)?;
+            }
+            opcode::I64_CLZ | opcode::I64_CTZ | opcode::I64_POPCNT | opcode::I64_BSWAP => {
+                let a = self.ps_pop_i64()?;
+                self.ps_push_i64(bits(op, a))?;
+            }
+            opcode::BITFIELD_EXTRACT => {
+                // ( value offset width -- field )
+                let width = self.ps_pop()?;
+                let offset = self.ps_pop()?;
+                let value = self.ps_pop()? as u32;
+                let (offset, mask) = bitfield(offset, width);
+                self.ps_push((value >> offset & mask) as i32)?;
+            }
+            opcode::BITFIELD_INSERT => {
+                // ( value field offset width -- value )
+                let width = self.ps_pop()?;
+                let offset = self.ps_pop()?;
+                let field = self.ps_pop()? as u32;
+                let value = self.ps_pop()? as u32;
+                let (offset, mask) = bitfield(offset, width);
+                let value = value & !(mask << offset) | (field & mask) << offset;
+                self.ps_push(value as i32)?;
             }
             opcode::DROP => {
-                self.pop_i32();
+                self.ps_pop()?;
             }
             opcode::DUP => {
-                let a = self.pop_i32();
-                self.push_i32(a);
-                self.push_i32(a);
+                let a = self.ps_pop()?;
+                self.ps_push(a)?;
+                self.ps_push(a)?;
             }
             opcode::SWAP => {
-                let a = self.pop_i32();
-                let b = self.pop_i32();
-                self.push_i32(a);
-                self.push_i32(b);
+                let a = self.ps_pop()?;
+                let b = self.ps_pop()?;
+                self.ps_push(a)?;
+                self.ps_push(b)?;
             }
             opcode::SELECT => {
                 unimplemented!()
             }
+            opcode::MEMORY_SIZE => {
+                let pages = self.memory_size();
+                self.ps_push(pages as i32)?;
+            }
+            opcode::MEMORY_GROW => {
+                // ( delta -- old_pages | -1 )
+                let delta = self.ps_pop()?;
+                let result = if delta < 0 {
+                    None
+                } else {
+                    self.grow_memory(delta as usize)
+                };
+                self.ps_push(result.map_or(-1, |pages| pages as i32))?;
+            }
             opcode::I32_LOAD => {
-                let addr = self.pop_i32() as usize;
+                let addr = self.ps_pop()? as usize;
+                self.guest_load(addr, 4)?;
                 let value = self.read_i32(addr);
-                self.push_i32(value);
+                self.ps_push(value)?;
             }
-            opcode::I32_LOAD_8 => {
-                let addr = self.pop_i32() as usize;
-                let value = self.read_u8(addr);
-                self.push_i32(value as i32);
-            }
-            opcode::I32_LOAD_16 => {
-                let addr = self.pop_i32() as usize;
-                let value = self.read_i16(addr);
-                self.push_i32(value as i32);
+            opcode::I32_LOAD_8
+            | opcode::I32_LOAD_8_S
+            | opcode::I32_LOAD_16
+            | opcode::I32_LOAD_16_U
+            | opcode::I64_LOAD_8
+            | opcode::I64_LOAD_8_S
+            | opcode::I64_LOAD_16
+            | opcode::I64_LOAD_16_S
+            | opcode::I64_LOAD_32
+            | opcode::I64_LOAD_32_S => {
+                let (len, signed) = narrow_load(op);
+                let addr = self.ps_pop()? as usize;
+                self.guest_load(addr, len)?;
+                let mut bytes = [0; 8];
+                self.read(addr, &mut bytes[..len]);
+                let shift = 64 - 8 * len as u32;
+                let value = i64::from_le_bytes(bytes) << shift;
+                let value = if signed {
+                    value >> shift
+                } else {
+                    (value as u64 >> shift) as i64
+                };
+                match op {
+                    opcode::I32_LOAD_8
+                    | opcode::I32_LOAD_8_S
+                    | opcode::I32_LOAD_16
+                    | opcode::I32_LOAD_16_U => self.ps_push(value as i32)?,
+                    _ => self.ps_push_i64(value)?,
+                }
             }
 
-            // TODO: impl I64 LOADS and STORES
+            // TODO: impl I64_LOAD and the I64 STORES
             opcode::I32_STORE => {
                 // ( value addr -- )
-                let addr = self.pop_i32() as usize;
-                let value = self.pop_i32();
-                // write_i32(&mut self.memory[addr..], value);
-                self.write_i32(value, addr);
+                let addr = self.ps_pop()? as usize;
+                let value = self.ps_pop()?;
+                self.guest_store(addr, 4, |vm| vm.write_i32(value, addr))?;
             }
             opcode::I32_STORE_8 => {
-                let addr = self.pop_i32() as usize;
-                let value = self.pop_i32();
-                self.write_u8(value as u8, addr);
+                let addr = self.ps_pop()? as usize;
+                let value = self.ps_pop()?;
+                self.guest_store(addr, 1, |vm| vm.write_u8(value as u8, addr))?;
             }
             opcode::I32_STORE_16 => {
-                let addr = self.pop_i32() as usize;
-                let value = self.pop_i32();
-                self.write_i16(value as i16, addr);
+                let addr = self.ps_pop()? as usize;
+                let value = self.ps_pop()?;
+                self.guest_store(addr, 2, |vm| vm.write_i16(value as i16, addr))?;
             }
             opcode::I32_CONST => {
-                let value = self.read_i32(*ip);
+                let value = self.fetch_i32(*ip)?;
                 *ip += 4;
-                self.push_i32(value);
+                self.ps_push(value)?;
             }
             opcode::I64_CONST => {
                 unimplemented!()
             }
-            opcode::EQ => {
-                let a = self.pop_i32();
-                let b = self.pop_i32();
-                self.push_i32(if a == b { TRUE } else { FALSE });
-            }
             opcode::EQZ => {
-                let a = self.pop_i32();
-                self.push_i32(if a == 0 { TRUE } else { FALSE });
-            }
-            opcode::NE => {
-                let a = self.pop_i32();
-                let b = self.pop_i32();
-                self.push_i32(if a != b { TRUE } else { FALSE });
-            }
-            //  1 10 < true
-            opcode::LT_S => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(if a < b { TRUE } else { FALSE });
-            }
-            opcode::LT_U => {
-                let b = self.pop_i32() as u32;
-                let a = self.pop_i32() as u32;
-                self.push_i32(if a < b { TRUE } else { FALSE });
-            }
-            opcode::GT_S => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(if a > b { TRUE } else { FALSE });
-            }
-            opcode::GT_U => {
-                let b = self.pop_i32() as u32;
-                let a = self.pop_i32() as u32;
-                self.push_i32(if a > b { TRUE } else { FALSE });
-            }
-            opcode::LE_S => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(if a <= b { TRUE } else { FALSE });
-            }
-            opcode::LE_U => {
-                let b = self.pop_i32() as u32;
-                let a = self.pop_i32() as u32;
-                self.push_i32(if a <= b { TRUE } else { FALSE });
-            }
-            opcode::GE_S => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(if a >= b { TRUE } else { FALSE });
-            }
-            opcode::GE_U => {
-                let b = self.pop_i32() as u32;
-                let a = self.pop_i32() as u32;
-                self.push_i32(if a >= b { TRUE } else { FALSE });
+                let a = self.ps_pop()?;
+                self.ps_push(if a == 0 { TRUE } else { FALSE })?;
+            }
+            opcode::EQ
+            | opcode::NE
+            | opcode::LT_S
+            | opcode::LT_U
+            | opcode::GT_S
+            | opcode::GT_U
+            | opcode::LE_S
+            | opcode::LE_U
+            | opcode::GE_S
+            | opcode::GE_U => {
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                self.ps_push(if compare_i32(op, a, b) { TRUE } else { FALSE })?;
             }
             // TODO: I64 ops
             opcode::ADD => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(a + b);
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                self.ps_push(a.wrapping_add(b))?;
             }
             opcode::SUB => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(a - b);
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                self.ps_push(a.wrapping_sub(b))?;
             }
             opcode::MUL => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(a * b);
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                self.ps_push(a.wrapping_mul(b))?;
             }
             opcode::DIV_S => {
                 // TODO: division by zero
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(a / b);
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                self.ps_push(a / b)?;
             }
             opcode::DIV_U => {
                 // TODO: division by zero
-                let b = self.pop_i32() as u32;
-                let a = self.pop_i32() as u32;
-                self.push_i32((a / b) as i32);
+                let b = self.ps_pop()? as u32;
+                let a = self.ps_pop()? as u32;
+                self.ps_push((a / b) as i32)?;
             }
             opcode::MOD_S => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(a % b);
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                self.ps_push(a % b)?;
             }
             opcode::MOD_U => {
-                let b = self.pop_i32() as u32;
-                let a = self.pop_i32() as u32;
-                self.push_i32((a % b) as i32);
+                let b = self.ps_pop()? as u32;
+                let a = self.ps_pop()? as u32;
+                self.ps_push((a % b) as i32)?;
+            }
+            opcode::ADD_SAT_S
+            | opcode::ADD_SAT_U
+            | opcode::SUB_SAT_S
+            | opcode::SUB_SAT_U
+            | opcode::MUL_SAT_S
+            | opcode::MUL_SAT_U
+            | opcode::MUL_HI_S
+            | opcode::MUL_HI_U => {
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                self.ps_push(arith_i32(op, a, b))?;
+            }
+            opcode::ADD_OVF_S
+            | opcode::ADD_OVF_U
+            | opcode::SUB_OVF_S
+            | opcode::SUB_OVF_U
+            | opcode::MUL_OVF_S
+            | opcode::MUL_OVF_U => {
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                let (value, overflow) = overflowing_i32(op, a, b);
+                self.ps_push(value)?;
+                self.ps_push(if overflow { TRUE } else { FALSE })?;
+            }
+            opcode::DIVMOD_S => {
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                if b == 0 {
+                    return Err(VmError::trap(Trap::DivisionByZero));
+                }
+                // i32::MIN / -1 wraps around to i32::MIN, remainder 0
+                self.ps_push(a.wrapping_rem(b))?;
+                self.ps_push(a.wrapping_div(b))?;
+            }
+            opcode::DIVMOD_U => {
+                let b = self.ps_pop()? as u32;
+                let a = self.ps_pop()? as u32;
+                let (Some(rem), Some(quot)) = (a.checked_rem(b), a.checked_div(b)) else {
+                    return Err(VmError::trap(Trap::DivisionByZero));
+                };
+                self.ps_push(rem as i32)?;
+                self.ps_push(quot as i32)?;
             }
             opcode::AND => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(a & b);
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                self.ps_push(a & b)?;
             }
             opcode::OR => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(a | b);
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                self.ps_push(a | b)?;
             }
             opcode::XOR => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(a ^ b);
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                self.ps_push(a ^ b)?;
             }
             opcode::SHL => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(a << b);
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                self.ps_push(a.wrapping_shl(b as u32))?;
             }
             opcode::SHR_S => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(a >> b);
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                self.ps_push(a.wrapping_shr(b as u32))?;
             }
             opcode::SHR_U => {
-                let b = self.pop_i32() as u32;
-                let a = self.pop_i32() as u32;
-                self.push_i32((a >> b) as i32);
+                let b = self.ps_pop()? as u32;
+                let a = self.ps_pop()? as u32;
+                self.ps_push(a.wrapping_shr(b) as i32)?;
             }
             opcode::ROTL => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(a.rotate_left(b as u32));
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                self.ps_push(a.rotate_left(b as u32))?;
             }
             opcode::ROTR => {
-                let b = self.pop_i32();
-                let a = self.pop_i32();
-                self.push_i32(a.rotate_right(b as u32));
+                let b = self.ps_pop()?;
+                let a = self.ps_pop()?;
+                self.ps_push(a.rotate_right(b as u32))?;
             }
             opcode::NOT => {
-                let a = self.pop_i32();
-                self.push_i32(!a);
+                let a = self.ps_pop()?;
+                self.ps_push(!a)?;
             }
             opcode::MIN => {
-                let a = self.pop_i32();
-                let b = self.pop_i32();
-                self.push_i32(a.min(b));
+                let a = self.ps_pop()?;
+                let b = self.ps_pop()?;
+                self.ps_push(a.min(b))?;
             }
             opcode::MAX => {
-                let a = self.pop_i32();
-                let b = self.pop_i32();
-                self.push_i32(a.max(b));
+                let a = self.ps_pop()?;
+                let b = self.ps_pop()?;
+                self.ps_push(a.max(b))?;
             }
 
             opcode::INC => {
-                let a = self.pop_i32();
-                self.push_i32(a + 1);
+                let a = self.ps_pop()?;
+                self.ps_push(a.wrapping_add(1))?;
             }
             opcode::DEC => {
-                let a = self.pop_i32();
-                self.push_i32(a - 1);
+                let a = self.ps_pop()?;
+                self.ps_push(a.wrapping_sub(1))?;
             }
             opcode::ZERO => {
-                self.push_i32(0);
+                self.ps_push(0)?;
             }
 
             _ => {
                 let handler = mem::take(&mut self.unknown_opcode_handler);
+                self.store_stack_pointers();
                 let mut handled = false;
                 for f in &handler {
                     if f(self, ip, op) {
@@ -455,8 +1157,9 @@ impl VM {
                     }
                 }
                 self.unknown_opcode_handler = handler;
+                self.load_stack_pointers();
                 if !handled {
-                    return Err(VmError::UnknownOp(op, *ip));
+                    return Err(VmError::trap(Trap::UnknownOp));
                 }
             }
         }
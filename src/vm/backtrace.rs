@@ -0,0 +1,74 @@
+use super::{VM, error::symbolize};
+use std::fmt;
+
+/// guest call stack, innermost first: the faulting instruction followed by the
+/// return addresses found on the rstack
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backtrace {
+    pub frames: Vec<usize>,
+    /// locals of the frames whose function did `ENTER`, same order as `frames`
+    pub locals: Vec<Option<Vec<i32>>>,
+}
+
+impl Backtrace {
+    /// names every frame after the closest symbol at or below it, `symbols` being
+    /// `(name, entry)` pairs of guest functions
+    pub fn symbolize(&self, symbols: &[(&str, usize)]) -> String {
+        let mut out = String::new();
+        for (i, addr) in self.frames.iter().enumerate() {
+            out += &match symbolize(symbols.iter().copied(), *addr) {
+                Some(symbol) => format!("#{i} {addr:#x} in {symbol}"),
+                None => format!("#{i} {addr:#x}"),
+            };
+            if let Some(Some(locals)) = self.locals.get(i) {
+                out += &format!(", locals {locals:?}");
+            }
+            out += "\n";
+        }
+        out
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.symbolize(&[]))
+    }
+}
+
+impl VM {
+    /// where the rstack starts, for `backtrace`. by default the rstack pointer
+    /// the first time guest code runs
+    pub fn set_rstack_base(&mut self, base: usize) {
+        self.rstack_base = Some(base);
+    }
+
+    /// guest call stack with `ip` as the innermost frame, from the rstack
+    /// contents between its base and current top
+    pub fn backtrace(&self, ip: usize) -> Backtrace {
+        let mut frames = vec![ip];
+        let top = self.read_i32(self.rstack_top) as usize;
+        if let Some(cs) = &self.call_stack {
+            frames.extend(cs.frames.iter().rev().map(|addr| *addr as usize));
+        } else {
+            let base = self.rstack_base.unwrap_or(top);
+            let mut addr = top + 4;
+            while addr <= base && addr + 4 <= self.memory.len() {
+                frames.push(self.read_i32(addr) as usize);
+                addr += 4;
+            }
+        }
+        // the innermost `ENTER` of a function wins, a frame at rstack depth d belongs
+        // to the function `depth - d` calls out
+        let depth = self.rstack_depth(top);
+        let mut locals = vec![None; frames.len()];
+        for frame in self.frames().into_iter().rev() {
+            if let Some(slot) = depth
+                .checked_sub(frame.rstack_depth)
+                .and_then(|i| locals.get_mut(i))
+            {
+                *slot = Some(frame.locals);
+            }
+        }
+        Backtrace { frames, locals }
+    }
+}
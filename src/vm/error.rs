@@ -0,0 +1,275 @@
+use super::VM;
+use crate::{Access, opcode};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum VmError {
+    /// guest code can not continue at the instruction described by `context`
+    Trap { trap: Trap, context: Box<Context> },
+    /// guest code called host function `index`, which was never added
+    UnknownVmFn { index: usize, context: Box<Context> },
+    /// a region was mapped over `start..end`, which overlaps one mapped before
+    RegionOverlap { start: usize, end: usize },
+    /// a region of `len` bytes was mapped at `start`, reaching past the end of memory
+    RegionOutOfBounds { start: usize, len: usize },
+    /// a global named `name` was declared before
+    DuplicateGlobal { name: String },
+    /// the host set a global that was never declared
+    UnknownGlobal { name: String },
+    /// the host replaced function `index`, which is not in the table
+    UnknownTableEntry { index: usize },
+    /// a table entry point at `entry`, past the end of memory
+    EntryOutOfBounds { entry: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Trap {
+    /// no unknown op handler took the opcode
+    UnknownOp,
+    Unreachable,
+    PermissionDenied {
+        addr: usize,
+        access: Access,
+    },
+    CallStackOverflow {
+        max_depth: usize,
+    },
+    CallStackUnderflow,
+    /// a relative jump to `target`, or a `BR_TABLE` through the entry at `target`,
+    /// outside memory
+    JumpOutOfRange {
+        target: i64,
+    },
+    /// `THROW` with no `CATCH` handler installed
+    Uncaught {
+        code: i32,
+    },
+    /// `ENTER` beyond the locals limit, see `VM::set_max_locals`
+    LocalsOverflow {
+        max_locals: usize,
+    },
+    /// `LEAVE` with no frame set up
+    NoFrame,
+    /// access to a local slot the innermost frame does not have, or with no frame
+    BadLocal {
+        index: usize,
+    },
+    /// access to a global that was not declared, or with the wrong type
+    BadGlobal {
+        index: usize,
+    },
+    ImmutableGlobal {
+        index: usize,
+    },
+    /// `CALL_INDIRECT` of a function the table does not have
+    TableOutOfRange {
+        index: usize,
+    },
+    /// `CALL_INDIRECT` expecting another signature than the function's
+    SignatureMismatch {
+        index: usize,
+    },
+    /// `DIVMOD_S` or `DIVMOD_U` by 0
+    DivisionByZero,
+}
+
+/// whom an error is down to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// the guest code
+    Trap,
+    /// the host functions given to the vm
+    Host,
+    /// how the vm was set up
+    Config,
+}
+
+/// state of the vm when guest code failed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Context {
+    /// address of the failing instruction
+    pub ip: usize,
+    pub op: u8,
+    /// items on the pstack and rstack, counted from where they were when guest code first ran
+    pub pstack_depth: usize,
+    pub rstack_depth: usize,
+    /// `name+offset` of `ip`, if the vm was given symbols
+    pub symbol: Option<String>,
+}
+
+impl Context {
+    pub fn mnemonic(&self) -> &'static str {
+        opcode::opcode(self.op)
+    }
+}
+
+impl VmError {
+    pub(super) fn trap(trap: Trap) -> Self {
+        VmError::Trap {
+            trap,
+            context: Box::default(),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            VmError::Trap { .. } => ErrorKind::Trap,
+            VmError::UnknownVmFn { .. } => ErrorKind::Host,
+            VmError::RegionOverlap { .. }
+            | VmError::RegionOutOfBounds { .. }
+            | VmError::DuplicateGlobal { .. }
+            | VmError::UnknownGlobal { .. }
+            | VmError::UnknownTableEntry { .. }
+            | VmError::EntryOutOfBounds { .. } => ErrorKind::Config,
+        }
+    }
+
+    pub fn context(&self) -> Option<&Context> {
+        match self {
+            VmError::Trap { context, .. } | VmError::UnknownVmFn { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    fn context_mut(&mut self) -> Option<&mut Context> {
+        match self {
+            VmError::Trap { context, .. } | VmError::UnknownVmFn { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::UnknownOp => write!(f, "unknown opcode"),
+            Trap::Unreachable => write!(f, "unreachable executed"),
+            Trap::PermissionDenied { addr, access } => {
+                let access = match access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                    Access::Execute => "execute",
+                };
+                write!(f, "{access} access to {addr:#x} denied")
+            }
+            Trap::CallStackOverflow { max_depth } => {
+                write!(f, "call stack overflow, more than {max_depth} frames")
+            }
+            Trap::CallStackUnderflow => write!(f, "call stack underflow"),
+            Trap::JumpOutOfRange { target } => {
+                write!(f, "jump to {target} outside memory")
+            }
+            Trap::Uncaught { code } => write!(f, "uncaught throw of {code}"),
+            Trap::LocalsOverflow { max_locals } => {
+                write!(f, "locals overflow, more than {max_locals} slots")
+            }
+            Trap::NoFrame => write!(f, "leave without a frame"),
+            Trap::BadLocal { index } => write!(f, "local {index} not in the frame"),
+            Trap::BadGlobal { index } => write!(f, "no global {index} of that type"),
+            Trap::ImmutableGlobal { index } => write!(f, "global {index} is immutable"),
+            Trap::TableOutOfRange { index } => write!(f, "no function {index} in the table"),
+            Trap::SignatureMismatch { index } => {
+                write!(f, "function {index} in the table has another signature")
+            }
+            Trap::DivisionByZero => write!(f, "division by zero"),
+        }
+    }
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at {:#x}", self.ip)?;
+        if let Some(symbol) = &self.symbol {
+            write!(f, " ({symbol})")?;
+        }
+        write!(
+            f,
+            " executing {} ({:#04x}), pstack depth {}, rstack depth {}",
+            self.mnemonic(),
+            self.op,
+            self.pstack_depth,
+            self.rstack_depth
+        )
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::Trap { trap, context } => write!(f, "{trap} {context}"),
+            VmError::UnknownVmFn { index, context } => {
+                write!(f, "unknown host function {index} {context}")
+            }
+            VmError::RegionOverlap { start, end } => {
+                write!(f, "region {start:#x}..{end:#x} overlaps a mapped region")
+            }
+            VmError::RegionOutOfBounds { start, len } => {
+                write!(
+                    f,
+                    "region of {len:#x} bytes at {start:#x} is past the end of memory"
+                )
+            }
+            VmError::DuplicateGlobal { name } => write!(f, "global {name} declared twice"),
+            VmError::UnknownGlobal { name } => write!(f, "no global named {name}"),
+            VmError::UnknownTableEntry { index } => write!(f, "no table entry {index}"),
+            VmError::EntryOutOfBounds { entry } => {
+                write!(f, "table entry point {entry:#x} is past the end of memory")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+// `name+offset` of the closest symbol at or below `addr`
+pub(super) fn symbolize<'a>(
+    symbols: impl IntoIterator<Item = (&'a str, usize)>,
+    addr: usize,
+) -> Option<String> {
+    let (name, entry) = symbols
+        .into_iter()
+        .filter(|(_, entry)| *entry <= addr)
+        .max_by_key(|(_, entry)| *entry)?;
+    Some(format!("{name}+{:#x}", addr - entry))
+}
+
+impl VM {
+    /// names guest functions, `(name, entry)`, for error contexts
+    pub fn set_symbols(&mut self, symbols: &[(&str, usize)]) {
+        self.symbols = symbols
+            .iter()
+            .map(|(name, entry)| (name.to_string(), *entry))
+            .collect();
+    }
+
+    // fills in the context of an error raised by the instruction at `ip`,
+    // while the cached stack pointers are still current
+    pub(super) fn with_context(&self, mut e: VmError, ip: usize) -> VmError {
+        if let Some(context) = e.context_mut() {
+            context.ip = ip;
+            context.op = self.memory.get(ip).copied().unwrap_or(0);
+            context.pstack_depth = self
+                .pstack_base
+                .unwrap_or(self.psp)
+                .saturating_sub(self.psp)
+                / 4;
+            context.rstack_depth = self.rstack_depth(self.rsp);
+            let symbols = self
+                .symbols
+                .iter()
+                .map(|(name, entry)| (name.as_str(), *entry));
+            context.symbol = symbolize(symbols, ip);
+        }
+        e
+    }
+
+    // return addresses on the rstack with its pointer at `rsp`, counted from its base
+    pub(super) fn rstack_depth(&self, rsp: usize) -> usize {
+        match &self.call_stack {
+            Some(cs) => cs.frames.len(),
+            None => self.rstack_base.unwrap_or(rsp).saturating_sub(rsp) / 4,
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use super::{Result, Trap, VM, VmError};
+
+/// a frame set up by `ENTER`, as seen by debuggers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// address of the `ENTER`
+    pub enter: usize,
+    pub locals: Vec<i32>,
+    /// rstack depth of the function owning the frame, as in `Context`
+    pub rstack_depth: usize,
+}
+
+// a frame without its locals, which follow `base` in `VM::locals`
+pub(super) struct FrameRecord {
+    enter: usize,
+    base: usize,
+    rstack_depth: usize,
+}
+
+pub(super) const MAX_LOCALS: usize = 0x10000;
+
+impl VM {
+    /// most local slots of all frames together, 0x10000 by default
+    pub fn set_max_locals(&mut self, max_locals: usize) {
+        self.max_locals = max_locals;
+    }
+
+    /// frames set up by `ENTER` and not left yet, innermost first. they are dropped
+    /// when `run` returns or `step` ends the code, so debuggers look at them from
+    /// host functions, unknown op handlers or between steps
+    pub fn frames(&self) -> Vec<Frame> {
+        let mut end = self.locals.len();
+        let mut frames = Vec::new();
+        for record in self.frames.iter().rev() {
+            frames.push(Frame {
+                enter: record.enter,
+                locals: self.locals[record.base..end].to_vec(),
+                rstack_depth: record.rstack_depth,
+            });
+            end = record.base;
+        }
+        frames
+    }
+
+    // `ENTER n` at `enter`
+    pub(super) fn enter(&mut self, enter: usize, n: usize) -> Result<()> {
+        let base = self.locals.len();
+        if base.saturating_add(n) > self.max_locals {
+            let max_locals = self.max_locals;
+            return Err(VmError::trap(Trap::LocalsOverflow { max_locals }));
+        }
+        self.locals.resize(base + n, 0);
+        let rstack_depth = self.rstack_depth(self.rsp);
+        self.frames.push(FrameRecord {
+            enter,
+            base,
+            rstack_depth,
+        });
+        Ok(())
+    }
+
+    pub(super) fn leave(&mut self) -> Result<()> {
+        let record = self.frames.pop().ok_or(VmError::trap(Trap::NoFrame))?;
+        self.locals.truncate(record.base);
+        Ok(())
+    }
+
+    // slot `index` of the innermost frame
+    pub(super) fn local(&mut self, index: usize) -> Result<&mut i32> {
+        let base = self.frames.last().map(|record| record.base);
+        match base.and_then(|base| self.locals[base..].get_mut(index)) {
+            Some(slot) => Ok(slot),
+            None => Err(VmError::trap(Trap::BadLocal { index })),
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use super::{Result, Trap, VM, VmError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    I32,
+    /// two cells on the pstack, laid out as in memory: the low word on top
+    I64,
+}
+
+/// a global declared with `VM::add_global`. globals live outside memory, guest
+/// code reaches them by index through `GLOBAL_GET` / `GLOBAL_SET` and their i64 variants
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Global {
+    pub name: String,
+    pub ty: ValueType,
+    /// whether guest code may set it, the host always can
+    pub mutable: bool,
+    /// sign-extended for i32 globals
+    pub value: i64,
+}
+
+// `value` as stored in a global of type `ty`
+fn wrap(ty: ValueType, value: i64) -> i64 {
+    match ty {
+        ValueType::I32 => value as i32 as i64,
+        ValueType::I64 => value,
+    }
+}
+
+impl VM {
+    /// declares a global with its initial value and returns its index, the
+    /// next one after the globals declared before
+    pub fn add_global(
+        &mut self,
+        name: &str,
+        ty: ValueType,
+        mutable: bool,
+        value: i64,
+    ) -> Result<usize> {
+        if self.globals.iter().any(|g| g.name == name) {
+            let name = name.to_string();
+            return Err(VmError::DuplicateGlobal { name });
+        }
+        self.globals.push(Global {
+            name: name.to_string(),
+            ty,
+            mutable,
+            value: wrap(ty, value),
+        });
+        Ok(self.globals.len() - 1)
+    }
+
+    pub fn globals(&self) -> &[Global] {
+        &self.globals
+    }
+
+    pub fn global(&self, name: &str) -> Option<i64> {
+        self.globals
+            .iter()
+            .find(|g| g.name == name)
+            .map(|g| g.value)
+    }
+
+    /// sets the global `name`, immutable or not, truncating `value` for an i32 global
+    pub fn set_global(&mut self, name: &str, value: i64) -> Result<()> {
+        let Some(global) = self.globals.iter_mut().find(|g| g.name == name) else {
+            let name = name.to_string();
+            return Err(VmError::UnknownGlobal { name });
+        };
+        global.value = wrap(global.ty, value);
+        Ok(())
+    }
+
+    // global `index` for an access of type `ty` by guest code
+    fn guest_global(&mut self, index: usize, ty: ValueType) -> Result<&mut Global> {
+        match self.globals.get_mut(index) {
+            Some(global) if global.ty == ty => Ok(global),
+            _ => Err(VmError::trap(Trap::BadGlobal { index })),
+        }
+    }
+
+    pub(super) fn global_get(&mut self, index: usize, ty: ValueType) -> Result<()> {
+        let value = self.guest_global(index, ty)?.value;
+        match ty {
+            ValueType::I32 => self.ps_push(value as i32),
+            ValueType::I64 => self.ps_push_i64(value),
+        }
+    }
+
+    pub(super) fn global_set(&mut self, index: usize, ty: ValueType) -> Result<()> {
+        let value = match ty {
+            ValueType::I32 => self.ps_pop()?.into(),
+            ValueType::I64 => self.ps_pop_i64()?,
+        };
+        let global = self.guest_global(index, ty)?;
+        if !global.mutable {
+            return Err(VmError::trap(Trap::ImmutableGlobal { index }));
+        }
+        global.value = value;
+        Ok(())
+    }
+}
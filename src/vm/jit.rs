@@ -0,0 +1,493 @@
+use super::VM;
+use std::collections::HashMap;
+
+// what native code sees of the vm. `psp` and `ip` are written back on exit
+#[repr(C)]
+struct State {
+    psp: u64,
+    memory_len: u64,
+    ip: u64,
+}
+
+pub(super) struct Jit {
+    threshold: u32,
+    calls: HashMap<usize, u32>,
+    // `None` for entries that could not be compiled
+    compiled: HashMap<usize, Option<backend::Native>>,
+    pending: Option<usize>,
+}
+
+impl Jit {
+    pub(super) fn new(threshold: u32) -> Self {
+        Jit {
+            threshold,
+            calls: HashMap::new(),
+            compiled: HashMap::new(),
+            pending: None,
+        }
+    }
+
+    pub(super) fn compiled(&self) -> usize {
+        self.compiled.values().filter(|f| f.is_some()).count()
+    }
+
+    pub(super) fn code_written(&mut self, addr: usize, len: usize) {
+        self.compiled.retain(|_, f| match f {
+            Some(f) => !(addr < f.code_end && f.code_start < addr + len),
+            None => true,
+        });
+    }
+}
+
+impl VM {
+    // called whenever guest code transfers control to a function entry
+    pub(super) fn jit_note_call(&mut self, entry: usize) {
+        if let Some(jit) = &mut self.jit {
+            jit.pending = Some(entry);
+        }
+    }
+
+    // runs the function `run` just called into natively, once it got hot.
+    // native code returns with `ip` at the first instruction it could not handle
+    pub(super) fn jit_enter(&mut self, ip: &mut usize) {
+        let Some(jit) = &mut self.jit else {
+            return;
+        };
+        let Some(entry) = jit.pending.take() else {
+            return;
+        };
+        // permission checks are not compiled in
+        if entry != *ip || !self.regions.is_empty() {
+            return;
+        }
+        // nor are bounds checks of the stack pointers themselves, guest code may
+        // have stored anything into their cells
+        let memory_len = self.memory.len();
+        let in_memory =
+            |sp: usize| 4 <= sp && sp.checked_add(4).is_some_and(|end| end <= memory_len);
+        if !in_memory(self.psp) || (self.call_stack.is_none() && !in_memory(self.rsp)) {
+            return;
+        }
+        if !jit.compiled.contains_key(&entry) {
+            let calls = jit.calls.entry(entry).or_insert(0);
+            *calls += 1;
+            if *calls < jit.threshold {
+                return;
+            }
+            let mut cells = vec![self.pstack_top];
+            if self.call_stack.is_none() {
+                cells.push(self.rstack_top);
+            }
+            let f = backend::compile(&self.memory, entry, &cells);
+            jit.compiled.insert(entry, f);
+        }
+        let Some(Some(f)) = jit.compiled.get(&entry) else {
+            return;
+        };
+        let mut state = State {
+            psp: self.psp as u64,
+            memory_len: self.memory.len() as u64,
+            ip: entry as u64,
+        };
+        f.call(&mut self.memory, &mut state);
+        self.psp = state.psp as usize;
+        *ip = state.ip as usize;
+    }
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+mod backend {
+    pub(super) struct Native {
+        pub(super) code_start: usize,
+        pub(super) code_end: usize,
+    }
+
+    impl Native {
+        pub(super) fn call(&self, _memory: &mut [u8], _state: &mut super::State) {}
+    }
+
+    pub(super) fn compile(_memory: &[u8], _entry: usize, _cells: &[usize]) -> Option<Native> {
+        None
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod backend {
+    use super::State;
+    use crate::{decode::decode, opcode};
+    use std::collections::{BTreeMap, HashMap};
+    use std::ffi::c_void;
+
+    unsafe extern "C" {
+        fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: i32,
+            flags: i32,
+            fd: i32,
+            off: i64,
+        ) -> *mut c_void;
+        fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+        fn munmap(addr: *mut c_void, len: usize) -> i32;
+    }
+
+    const PROT_READ: i32 = 1;
+    const PROT_WRITE: i32 = 2;
+    const PROT_EXEC: i32 = 4;
+    const MAP_PRIVATE: i32 = 2;
+    const MAP_ANONYMOUS: i32 = 0x20;
+
+    type Entry = unsafe extern "sysv64" fn(*mut u8, *mut State);
+
+    pub(super) struct Native {
+        ptr: *mut c_void,
+        len: usize,
+        pub(super) code_start: usize,
+        pub(super) code_end: usize,
+    }
+
+    impl Native {
+        fn new(code: &[u8], code_start: usize, code_end: usize) -> Option<Native> {
+            let len = code.len();
+            unsafe {
+                let ptr = mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    PROT_READ | PROT_WRITE,
+                    MAP_PRIVATE | MAP_ANONYMOUS,
+                    -1,
+                    0,
+                );
+                if ptr as isize == -1 {
+                    return None;
+                }
+                std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, len);
+                if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                    munmap(ptr, len);
+                    return None;
+                }
+                Some(Native {
+                    ptr,
+                    len,
+                    code_start,
+                    code_end,
+                })
+            }
+        }
+
+        pub(super) fn call(&self, memory: &mut [u8], state: &mut State) {
+            unsafe {
+                let f: Entry = std::mem::transmute(self.ptr);
+                f(memory.as_mut_ptr(), state);
+            }
+        }
+    }
+
+    impl Drop for Native {
+        fn drop(&mut self) {
+            unsafe {
+                munmap(self.ptr, self.len);
+            }
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    enum Target {
+        Instr(usize),
+        Exit(usize),
+    }
+
+    // registers: rdi memory, rsi state, r8 pstack pointer, r9 memory length,
+    // eax / ecx / edx scratch
+    const EAX: u8 = 0;
+    const ECX: u8 = 1;
+
+    struct Asm {
+        code: Vec<u8>,
+        labels: HashMap<usize, usize>,
+        fixups: Vec<(usize, Target)>,
+    }
+
+    impl Asm {
+        fn emit(&mut self, bytes: &[u8]) {
+            self.code.extend_from_slice(bytes);
+        }
+
+        // mov reg32, [rdi + r8 + disp]
+        fn load_stack(&mut self, reg: u8, disp: i32) {
+            self.emit(&[0x42, 0x8b, 0x84 | (reg << 3), 0x07]);
+            self.emit(&disp.to_le_bytes());
+        }
+
+        // mov [rdi + r8 + disp], reg32
+        fn store_stack(&mut self, reg: u8, disp: i32) {
+            self.emit(&[0x42, 0x89, 0x84 | (reg << 3), 0x07]);
+            self.emit(&disp.to_le_bytes());
+        }
+
+        // add r8, n
+        fn add_psp(&mut self, n: i8) {
+            self.emit(&[0x49, 0x83, 0xc0, n as u8]);
+        }
+
+        fn jump(&mut self, opcode: &[u8], target: Target) {
+            self.emit(opcode);
+            self.fixups.push((self.code.len(), target));
+            self.emit(&[0; 4]);
+        }
+
+        // exits unless `pops` cells can be read and the results written
+        fn check_stack(&mut self, ip: usize, pops: i32, pushes: i32) {
+            if pops == 0 && pushes == 0 {
+                return;
+            }
+            // lea rax, [r8 + 4 * pops + 4]; cmp rax, r9; ja exit
+            self.emit(&[0x49, 0x8d, 0x80]);
+            self.emit(&(4 * pops + 4).to_le_bytes());
+            self.emit(&[0x4c, 0x39, 0xc8]);
+            self.jump(&[0x0f, 0x87], Target::Exit(ip));
+            if pushes > pops {
+                // cmp r8, 4; jb exit
+                self.emit(&[0x49, 0x83, 0xf8, 0x04]);
+                self.jump(&[0x0f, 0x82], Target::Exit(ip));
+            }
+        }
+
+        // ( a b -- r ) with a in eax and b in ecx, `op` leaving r in eax
+        fn binary(&mut self, op: &[u8]) {
+            self.load_stack(EAX, 8);
+            self.load_stack(ECX, 4);
+            self.emit(op);
+            self.store_stack(EAX, 8);
+            self.add_psp(4);
+        }
+
+        // ( a -- r )
+        fn unary(&mut self, op: &[u8]) {
+            self.load_stack(EAX, 4);
+            self.emit(op);
+            self.store_stack(EAX, 4);
+        }
+
+        fn push_const(&mut self, value: i32) {
+            // mov dword [rdi + r8], value; sub r8, 4
+            self.emit(&[0x42, 0xc7, 0x84, 0x07]);
+            self.emit(&0i32.to_le_bytes());
+            self.emit(&value.to_le_bytes());
+            self.emit(&[0x49, 0x83, 0xe8, 0x04]);
+        }
+
+        fn exit_stub(&mut self, ip: usize) {
+            // mov [rsi], r8; mov qword [rsi + 16], ip; ret
+            self.emit(&[0x4c, 0x89, 0x06]);
+            self.emit(&[0x48, 0xc7, 0x46, 0x10]);
+            self.emit(&(ip as i32).to_le_bytes());
+            self.emit(&[0xc3]);
+        }
+    }
+
+    // cmp eax, ecx; setcc al; movzx eax, al
+    fn compare(setcc: u8) -> [u8; 8] {
+        [0x39, 0xc8, 0x0f, setcc, 0xc0, 0x0f, 0xb6, 0xc0]
+    }
+
+    fn setcc(op: u8) -> Option<u8> {
+        Some(match op {
+            opcode::EQ => 0x94,
+            opcode::NE => 0x95,
+            opcode::LT_S => 0x9c,
+            opcode::LT_U => 0x92,
+            opcode::GT_S => 0x9f,
+            opcode::GT_U => 0x97,
+            opcode::LE_S => 0x9e,
+            opcode::LE_U => 0x96,
+            opcode::GE_S => 0x9d,
+            opcode::GE_U => 0x93,
+            _ => return None,
+        })
+    }
+
+    fn binary_op(op: u8) -> Option<&'static [u8]> {
+        Some(match op {
+            opcode::ADD => &[0x01, 0xc8],
+            opcode::SUB => &[0x29, 0xc8],
+            opcode::MUL => &[0x0f, 0xaf, 0xc1],
+            opcode::AND => &[0x21, 0xc8],
+            opcode::OR => &[0x09, 0xc8],
+            opcode::XOR => &[0x31, 0xc8],
+            opcode::SHL => &[0xd3, 0xe0],
+            opcode::SHR_S => &[0xd3, 0xf8],
+            opcode::SHR_U => &[0xd3, 0xe8],
+            opcode::ROTL => &[0xd3, 0xc0],
+            opcode::ROTR => &[0xd3, 0xc8],
+            // cmp eax, ecx; cmovg / cmovl eax, ecx
+            opcode::MIN => &[0x39, 0xc8, 0x0f, 0x4f, 0xc1],
+            opcode::MAX => &[0x39, 0xc8, 0x0f, 0x4c, 0xc1],
+            _ => return None,
+        })
+    }
+
+    fn unary_op(op: u8) -> Option<&'static [u8]> {
+        Some(match op {
+            opcode::NOT => &[0xf7, 0xd0],
+            opcode::INC => &[0x83, 0xc0, 0x01],
+            opcode::DEC => &[0x83, 0xe8, 0x01],
+            // test eax, eax; sete al; movzx eax, al
+            opcode::EQZ => &[0x85, 0xc0, 0x0f, 0x94, 0xc0, 0x0f, 0xb6, 0xc0],
+            _ => return None,
+        })
+    }
+
+    fn is_supported(op: u8) -> bool {
+        setcc(op).is_some()
+            || binary_op(op).is_some()
+            || unary_op(op).is_some()
+            || matches!(
+                op,
+                opcode::NOP
+                    | opcode::DROP
+                    | opcode::DUP
+                    | opcode::SWAP
+                    | opcode::ZERO
+                    | opcode::I32_CONST
+                    | opcode::I32_LOAD
+                    | opcode::BRI
+                    | opcode::BRZI
+                    | opcode::JMPI
+                    | opcode::JZI
+            )
+    }
+
+    // compiles everything reachable from `entry` without leaving the function,
+    // any other instruction becomes an exit back to the interpreter.
+    // `cells` are the stack pointer cells, loads from them are left to the interpreter
+    pub(super) fn compile(memory: &[u8], entry: usize, cells: &[usize]) -> Option<Native> {
+        let mut body = BTreeMap::new();
+        let mut work = vec![entry];
+        while let Some(addr) = work.pop() {
+            if body.contains_key(&addr) {
+                continue;
+            }
+            let Some(instr) = decode(memory, addr) else {
+                continue;
+            };
+            // jumps out of memory are left to the interpreter to report
+            let out_of_range = instr.target().is_some_and(|target| target >= memory.len());
+            if !is_supported(instr.op) || instr.next() > memory.len() || out_of_range {
+                continue;
+            }
+            body.insert(addr, instr);
+            if !matches!(instr.op, opcode::BRI | opcode::JMPI) {
+                work.push(instr.next());
+            }
+            if let Some(target) = instr.target() {
+                work.push(target);
+            }
+        }
+        if body.is_empty() {
+            return None;
+        }
+
+        let mut asm = Asm {
+            code: Vec::new(),
+            labels: HashMap::new(),
+            fixups: Vec::new(),
+        };
+        // mov r8, [rsi]; mov r9, [rsi + 8]
+        asm.emit(&[0x4c, 0x8b, 0x06, 0x4c, 0x8b, 0x4e, 0x08]);
+
+        let mut addrs = body.keys().copied().peekable();
+        while let Some(addr) = addrs.next() {
+            let instr = body[&addr];
+            asm.labels.insert(addr, asm.code.len());
+            let target = instr.target().unwrap_or(0);
+            let imm = instr.imm_i32();
+            match instr.op {
+                opcode::NOP => {}
+                opcode::DROP => {
+                    asm.check_stack(addr, 1, 0);
+                    asm.add_psp(4);
+                }
+                opcode::DUP => {
+                    asm.check_stack(addr, 1, 2);
+                    asm.load_stack(EAX, 4);
+                    asm.store_stack(EAX, 0);
+                    asm.add_psp(-4);
+                }
+                opcode::SWAP => {
+                    asm.check_stack(addr, 2, 2);
+                    asm.load_stack(EAX, 4);
+                    asm.load_stack(ECX, 8);
+                    asm.store_stack(ECX, 4);
+                    asm.store_stack(EAX, 8);
+                }
+                opcode::ZERO | opcode::I32_CONST => {
+                    asm.check_stack(addr, 0, 1);
+                    asm.push_const(imm);
+                }
+                opcode::I32_LOAD => {
+                    asm.check_stack(addr, 1, 1);
+                    asm.load_stack(EAX, 4);
+                    // lea rdx, [rax + 4]; cmp rdx, r9; ja exit
+                    asm.emit(&[0x48, 0x8d, 0x50, 0x04, 0x4c, 0x39, 0xca]);
+                    asm.jump(&[0x0f, 0x87], Target::Exit(addr));
+                    for &cell in cells {
+                        // lea rdx, [rax + 3]; sub rdx, cell; cmp rdx, 7; jb exit
+                        asm.emit(&[0x48, 0x8d, 0x50, 0x03, 0x48, 0x81, 0xea]);
+                        asm.emit(&(cell as i32).to_le_bytes());
+                        asm.emit(&[0x48, 0x83, 0xfa, 0x07]);
+                        asm.jump(&[0x0f, 0x82], Target::Exit(addr));
+                    }
+                    // mov eax, [rdi + rax]
+                    asm.emit(&[0x8b, 0x04, 0x07]);
+                    asm.store_stack(EAX, 4);
+                }
+                opcode::BRI | opcode::JMPI => {
+                    asm.jump(&[0xe9], Target::Instr(target));
+                    continue;
+                }
+                opcode::BRZI | opcode::JZI => {
+                    asm.check_stack(addr, 1, 0);
+                    asm.load_stack(EAX, 4);
+                    asm.add_psp(4);
+                    // test eax, eax; jz target
+                    asm.emit(&[0x85, 0xc0]);
+                    asm.jump(&[0x0f, 0x84], Target::Instr(target));
+                }
+                op => {
+                    if let Some(setcc) = setcc(op) {
+                        asm.check_stack(addr, 2, 1);
+                        asm.binary(&compare(setcc));
+                    } else if let Some(code) = binary_op(op) {
+                        asm.check_stack(addr, 2, 1);
+                        asm.binary(code);
+                    } else if let Some(code) = unary_op(op) {
+                        asm.check_stack(addr, 1, 1);
+                        asm.unary(code);
+                    }
+                }
+            }
+            if addrs.peek() != Some(&instr.next()) {
+                asm.jump(&[0xe9], Target::Instr(instr.next()));
+            }
+        }
+
+        let mut exits = HashMap::new();
+        for (pos, target) in std::mem::take(&mut asm.fixups) {
+            let dest = match target {
+                Target::Instr(addr) if asm.labels.contains_key(&addr) => asm.labels[&addr],
+                Target::Instr(ip) | Target::Exit(ip) => *exits.entry(ip).or_insert_with(|| {
+                    let at = asm.code.len();
+                    asm.exit_stub(ip);
+                    at
+                }),
+            };
+            let rel = dest as i32 - (pos as i32 + 4);
+            asm.code[pos..pos + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+
+        let code_start = *body.keys().next()?;
+        let code_end = body.values().map(|i| i.next()).max()?;
+        Native::new(&asm.code, code_start, code_end)
+    }
+}
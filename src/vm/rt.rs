@@ -0,0 +1,85 @@
+//! the primitives the code generated by `aot::rust` is made of. they are only
+//! meant to be called by generated code, from the function given to `run`.
+//!
+//! while `run` runs, the vm keeps the stack pointers out of their cells in memory.
+//! the stack ops here work on those and are only correct from inside `run`:
+//! outside of it they use whatever pointers the last run left behind and the
+//! cells in memory are not updated. they are hidden from the docs for that
+
+use super::{Result, VM};
+use crate::Access;
+
+/// runs generated code from `ip` until `END`, like `VM::run`. `dispatch` runs the
+/// code at `ip` and returns like `VM::step`, ideally for more than one instruction
+pub fn run(
+    vm: &mut VM,
+    ip: &mut usize,
+    dispatch: fn(&mut VM, &mut usize) -> Result<bool>,
+) -> Result<()> {
+    vm.load_stack_pointers();
+    let result = loop {
+        match dispatch(vm, ip) {
+            Ok(true) => {}
+            Ok(false) => break Ok(()),
+            Err(e) => break Err(vm.with_context(e, vm.at)),
+        }
+    };
+    vm.end_run();
+    vm.store_stack_pointers();
+    result
+}
+
+/// starts the instruction at `addr`, checking its opcode may be executed
+pub fn at(vm: &mut VM, addr: usize) -> Result<()> {
+    vm.at = addr;
+    vm.check_access(addr, 1, Access::Execute)
+}
+
+/// checks the i32 immediate at `addr` may be executed
+pub fn fetch(vm: &VM, addr: usize) -> Result<()> {
+    vm.check_access(addr, 4, Access::Execute)
+}
+
+/// only correct inside `run`, see the module docs
+#[doc(hidden)]
+pub fn push(vm: &mut VM, value: i32) -> Result<()> {
+    vm.ps_push(value)
+}
+
+/// only correct inside `run`, see the module docs
+#[doc(hidden)]
+pub fn pop(vm: &mut VM) -> Result<i32> {
+    vm.ps_pop()
+}
+
+/// only correct inside `run`, see the module docs
+#[doc(hidden)]
+pub fn rs_push(vm: &mut VM, value: i32) -> Result<()> {
+    vm.rs_push(value)
+}
+
+/// only correct inside `run`, see the module docs
+#[doc(hidden)]
+pub fn rs_pop(vm: &mut VM) -> Result<i32> {
+    vm.rs_pop()
+}
+
+/// drops the `CATCH` handlers installed by the function being left
+pub fn leave(vm: &mut VM) {
+    vm.leave_handlers()
+}
+
+/// interprets the instruction at `ip`, for code that was not translated.
+/// only correct inside `run`, see the module docs
+#[doc(hidden)]
+pub fn exec(vm: &mut VM, ip: &mut usize) -> Result<bool> {
+    vm.at = *ip;
+    vm.exec(ip)
+}
+
+/// interprets `op`, started with `at`, with `ip` past the opcode.
+/// only correct inside `run`, see the module docs
+#[doc(hidden)]
+pub fn execute(vm: &mut VM, op: u8, ip: &mut usize) -> Result<bool> {
+    vm.execute(op, ip)
+}
@@ -0,0 +1,77 @@
+use super::{Result, Trap, VM, VmError};
+
+/// cells a function takes from and leaves on the pstack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    pub params: u16,
+    pub results: u16,
+}
+
+impl Signature {
+    /// the immediate of `CALL_INDIRECT`, `params | results << 16`
+    pub fn encode(self) -> i32 {
+        (u32::from(self.params) | u32::from(self.results) << 16) as i32
+    }
+
+    pub fn decode(imm: i32) -> Self {
+        Signature {
+            params: imm as u16,
+            results: (imm as u32 >> 16) as u16,
+        }
+    }
+}
+
+/// an entry point guest code may reach with `CALL_INDIRECT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableEntry {
+    pub entry: usize,
+    pub signature: Signature,
+}
+
+impl VM {
+    /// appends a function to the table and returns its index. `entry` is an address
+    /// in memory as the code is run, after `optimize` or `structured::lower`, and
+    /// has to be inside memory as it is now
+    pub fn add_table_entry(&mut self, entry: usize, signature: Signature) -> Result<usize> {
+        self.check_entry(entry)?;
+        self.table.push(TableEntry { entry, signature });
+        Ok(self.table.len() - 1)
+    }
+
+    /// replaces the function at `index`
+    pub fn set_table_entry(
+        &mut self,
+        index: usize,
+        entry: usize,
+        signature: Signature,
+    ) -> Result<()> {
+        self.check_entry(entry)?;
+        let Some(function) = self.table.get_mut(index) else {
+            return Err(VmError::UnknownTableEntry { index });
+        };
+        *function = TableEntry { entry, signature };
+        Ok(())
+    }
+
+    fn check_entry(&self, entry: usize) -> Result<()> {
+        if entry >= self.memory.len() {
+            return Err(VmError::EntryOutOfBounds { entry });
+        }
+        Ok(())
+    }
+
+    pub fn table(&self) -> &[TableEntry] {
+        &self.table
+    }
+
+    // entry of function `index` for `CALL_INDIRECT` expecting `signature`
+    pub(super) fn indirect_target(&self, index: usize, signature: Signature) -> Result<usize> {
+        let Some(function) = self.table.get(index) else {
+            return Err(VmError::trap(Trap::TableOutOfRange { index }));
+        };
+        if function.signature != signature {
+            return Err(VmError::trap(Trap::SignatureMismatch { index }));
+        }
+        Ok(function.entry)
+    }
+}
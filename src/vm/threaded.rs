@@ -0,0 +1,474 @@
+use super::{FALSE, Result, TRUE, VM, arith_i32, compare_i32};
+use crate::{Access, decode::decode, opcode};
+use std::{ops::Range, rc::Rc};
+
+type Handler = fn(&mut VM, &mut usize, &Decoded) -> Result<bool>;
+
+// an instruction with its immediate and branch target resolved.
+// `handler` is picked once at decode time so dispatch is a single indirect call.
+// a fused pair of instructions is described by the slot of its first instruction,
+// with `target` and `next` taken from the second one
+#[derive(Clone, Copy)]
+pub(super) struct Decoded {
+    handler: Handler,
+    op: u8,
+    addr: usize,
+    imm: i32,
+    target: usize,
+    next: usize,
+    pub(super) fused: bool,
+}
+
+pub(super) struct CodeCache {
+    range: Range<usize>,
+    // indexed by `addr - range.start`, `None` for bytes that are not the start of an instruction.
+    // shared so `run_cached` can hold on to it while handlers borrow the vm
+    slots: Rc<[Option<Decoded>]>,
+    fused: usize,
+    valid: bool,
+}
+
+impl CodeCache {
+    pub(super) fn new(range: Range<usize>) -> Self {
+        CodeCache {
+            range,
+            slots: Rc::new([]),
+            fused: 0,
+            valid: false,
+        }
+    }
+
+    pub(super) fn overlaps(&self, addr: usize, len: usize) -> bool {
+        addr < self.range.end && self.range.start < addr + len
+    }
+
+    pub(super) fn invalidate(&mut self) {
+        self.valid = false;
+    }
+
+    pub(super) fn fused(&self) -> usize {
+        self.fused
+    }
+
+    fn get(&self, ip: usize) -> Option<Decoded> {
+        slot(&self.slots, self.range.start, ip).copied()
+    }
+}
+
+impl VM {
+    pub(super) fn cached_instruction(&mut self, ip: usize) -> Option<Decoded> {
+        let cache = self.code_cache.as_ref()?;
+        if !cache.valid {
+            self.decode_code_cache();
+        }
+        self.code_cache.as_ref()?.get(ip)
+    }
+
+    fn decode_code_cache(&mut self) {
+        let Some(mut cache) = self.code_cache.take() else {
+            return;
+        };
+        let end = cache.range.end.min(self.memory.len());
+        let mut slots = vec![None; cache.range.len()];
+        cache.fused = 0;
+
+        let mut addr = cache.range.start;
+        while addr < end {
+            let Some(instr) = decode(&self.memory, addr) else {
+                break;
+            };
+            // leave non executable code to `exec` so it reports the violation
+            if instr.next() > end
+                || self
+                    .check_access(addr, instr.size(), Access::Execute)
+                    .is_err()
+            {
+                addr += 1;
+                continue;
+            }
+            let imm = instr.imm_i32();
+            let handler = match instr.target() {
+                // `execute` reports relative jumps out of memory
+                Some(target) if target >= self.memory.len() => generic,
+                _ => handler(instr.op),
+            };
+            slots[addr - cache.range.start] = Some(Decoded {
+                handler,
+                op: instr.op,
+                addr,
+                imm,
+                target: instr.target().unwrap_or(0),
+                next: instr.next(),
+                fused: false,
+            });
+            addr = instr.next();
+        }
+
+        // fused handlers skip the stack traffic between the pair, which is only
+        // unobservable as long as no region can make a stack access fail
+        if self.regions.is_empty() {
+            let start = cache.range.start;
+            let mut addr = start;
+            while addr < end {
+                let Some(first) = slots[addr - start] else {
+                    addr += 1;
+                    continue;
+                };
+                let second = slots.get(first.next - start).copied().flatten();
+                if let Some(second) = second
+                    && let Some(handler) = fuse(first.op, second.op)
+                {
+                    let target = if second.op == opcode::BRZI {
+                        second.target
+                    } else {
+                        first.target
+                    };
+                    slots[addr - start] = Some(Decoded {
+                        handler,
+                        target,
+                        next: second.next,
+                        fused: true,
+                        ..first
+                    });
+                    cache.fused += 1;
+                }
+                addr = first.next;
+            }
+        }
+        cache.slots = slots.into();
+        cache.valid = true;
+        self.code_cache = Some(cache);
+    }
+
+    pub(super) fn dispatch(&mut self, ip: &mut usize, d: &Decoded) -> Result<bool> {
+        (d.handler)(self, ip, d)
+    }
+
+    // runs cached instructions from `ip` until one is not cached, the cache is stale
+    // or the code ends. `at` is left at the instruction that failed
+    pub(super) fn run_cached(&mut self, ip: &mut usize, at: &mut usize) -> Result<bool> {
+        let Some(cache) = &self.code_cache else {
+            return Ok(true);
+        };
+        let (slots, start) = (cache.slots.clone(), cache.range.start);
+        // a host function may have invalidated the cache, or decoded it again
+        while self
+            .code_cache
+            .as_ref()
+            .is_some_and(|cache| cache.valid && Rc::ptr_eq(&cache.slots, &slots))
+            && let Some(d) = slot(&slots, start, *ip)
+        {
+            *at = *ip;
+            if !(d.handler)(self, ip, d)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn slot(slots: &[Option<Decoded>], start: usize, ip: usize) -> Option<&Decoded> {
+    slots.get(ip.wrapping_sub(start))?.as_ref()
+}
+
+// the ops without a handler of their own go through `execute`
+fn handler(op: u8) -> Handler {
+    match op {
+        opcode::NOP => nop,
+        opcode::END => end,
+        opcode::I32_CONST => i32_const,
+        opcode::ZERO => zero,
+        opcode::DROP => drop,
+        opcode::DUP => dup,
+        opcode::SWAP => swap,
+        opcode::BRI | opcode::JMPI => jump,
+        opcode::BRZI | opcode::JZI => jump_if_zero,
+        opcode::CALLI => calli,
+        opcode::RETURN => ret,
+        opcode::I32_LOAD => load,
+        opcode::I32_STORE => store,
+        opcode::LOCAL_GET => local_get,
+        opcode::LOCAL_SET => local_set::<false>,
+        opcode::LOCAL_TEE => local_set::<true>,
+        opcode::INC => unary::<{ opcode::INC }>,
+        opcode::DEC => unary::<{ opcode::DEC }>,
+        opcode::NOT => unary::<{ opcode::NOT }>,
+        opcode::EQZ => unary::<{ opcode::EQZ }>,
+        opcode::ADD => binary::<{ opcode::ADD }>,
+        opcode::SUB => binary::<{ opcode::SUB }>,
+        opcode::MUL => binary::<{ opcode::MUL }>,
+        opcode::AND => binary::<{ opcode::AND }>,
+        opcode::OR => binary::<{ opcode::OR }>,
+        opcode::XOR => binary::<{ opcode::XOR }>,
+        opcode::SHL => binary::<{ opcode::SHL }>,
+        opcode::SHR_S => binary::<{ opcode::SHR_S }>,
+        opcode::SHR_U => binary::<{ opcode::SHR_U }>,
+        opcode::ROTL => binary::<{ opcode::ROTL }>,
+        opcode::ROTR => binary::<{ opcode::ROTR }>,
+        opcode::MIN => binary::<{ opcode::MIN }>,
+        opcode::MAX => binary::<{ opcode::MAX }>,
+        opcode::EQ => binary::<{ opcode::EQ }>,
+        opcode::NE => binary::<{ opcode::NE }>,
+        opcode::LT_S => binary::<{ opcode::LT_S }>,
+        opcode::LT_U => binary::<{ opcode::LT_U }>,
+        opcode::GT_S => binary::<{ opcode::GT_S }>,
+        opcode::GT_U => binary::<{ opcode::GT_U }>,
+        opcode::LE_S => binary::<{ opcode::LE_S }>,
+        opcode::LE_U => binary::<{ opcode::LE_U }>,
+        opcode::GE_S => binary::<{ opcode::GE_S }>,
+        opcode::GE_U => binary::<{ opcode::GE_U }>,
+        opcode::ADD_SAT_S => binary::<{ opcode::ADD_SAT_S }>,
+        opcode::ADD_SAT_U => binary::<{ opcode::ADD_SAT_U }>,
+        opcode::SUB_SAT_S => binary::<{ opcode::SUB_SAT_S }>,
+        opcode::SUB_SAT_U => binary::<{ opcode::SUB_SAT_U }>,
+        opcode::MUL_SAT_S => binary::<{ opcode::MUL_SAT_S }>,
+        opcode::MUL_SAT_U => binary::<{ opcode::MUL_SAT_U }>,
+        opcode::MUL_HI_S => binary::<{ opcode::MUL_HI_S }>,
+        opcode::MUL_HI_U => binary::<{ opcode::MUL_HI_U }>,
+        _ => generic,
+    }
+}
+
+// `a op b` for the ops `binary` is instantiated for, as `execute` does it
+fn binary_i32(op: u8, a: i32, b: i32) -> i32 {
+    match op {
+        opcode::ADD => a.wrapping_add(b),
+        opcode::SUB => a.wrapping_sub(b),
+        opcode::MUL => a.wrapping_mul(b),
+        opcode::AND => a & b,
+        opcode::OR => a | b,
+        opcode::XOR => a ^ b,
+        opcode::SHL => a.wrapping_shl(b as u32),
+        opcode::SHR_S => a.wrapping_shr(b as u32),
+        opcode::SHR_U => (a as u32).wrapping_shr(b as u32) as i32,
+        opcode::ROTL => a.rotate_left(b as u32),
+        opcode::ROTR => a.rotate_right(b as u32),
+        opcode::MIN => a.min(b),
+        opcode::MAX => a.max(b),
+        _ if is_compare(op) => {
+            if compare_i32(op, a, b) {
+                TRUE
+            } else {
+                FALSE
+            }
+        }
+        _ => arith_i32(op, a, b),
+    }
+}
+
+fn is_compare(op: u8) -> bool {
+    matches!(
+        op,
+        opcode::EQ
+            | opcode::NE
+            | opcode::LT_S
+            | opcode::LT_U
+            | opcode::GT_S
+            | opcode::GT_U
+            | opcode::LE_S
+            | opcode::LE_U
+            | opcode::GE_S
+            | opcode::GE_U
+    )
+}
+
+// an error in a fused handler has to report the ip the unfused pair would.
+// the second instruction of every arithmetic pair is a single byte, so that is `next`
+fn fuse(first: u8, second: u8) -> Option<Handler> {
+    match (first, second) {
+        (opcode::I32_CONST, opcode::ADD) => Some(const_add),
+        (opcode::I32_CONST, opcode::I32_LOAD) => Some(const_load),
+        (opcode::DUP, opcode::MUL) => Some(dup_mul),
+        (opcode::EQZ, opcode::BRZI) => Some(eqz_brzi),
+        (op, opcode::BRZI) if is_compare(op) => Some(compare_brzi),
+        _ => None,
+    }
+}
+
+fn generic(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.addr + 1;
+    vm.execute(d.op, ip)
+}
+
+fn nop(_vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.next;
+    Ok(true)
+}
+
+fn end(_vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.next;
+    Ok(false)
+}
+
+fn i32_const(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.next;
+    vm.ps_push(d.imm)?;
+    Ok(true)
+}
+
+fn zero(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.next;
+    vm.ps_push(0)?;
+    Ok(true)
+}
+
+fn drop(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.next;
+    vm.ps_pop()?;
+    Ok(true)
+}
+
+fn dup(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.next;
+    let a = vm.ps_pop()?;
+    vm.ps_push(a)?;
+    vm.ps_push(a)?;
+    Ok(true)
+}
+
+fn swap(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.next;
+    let a = vm.ps_pop()?;
+    let b = vm.ps_pop()?;
+    vm.ps_push(a)?;
+    vm.ps_push(b)?;
+    Ok(true)
+}
+
+// INC, DEC, NOT, EQZ
+fn unary<const OP: u8>(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.next;
+    let a = vm.ps_pop()?;
+    let value = match OP {
+        opcode::INC => a.wrapping_add(1),
+        opcode::DEC => a.wrapping_sub(1),
+        opcode::NOT => !a,
+        _ => {
+            if a == 0 {
+                TRUE
+            } else {
+                FALSE
+            }
+        }
+    };
+    vm.ps_push(value)?;
+    Ok(true)
+}
+
+// ( a b -- a op b )
+fn binary<const OP: u8>(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.next;
+    let b = vm.ps_pop()?;
+    let a = vm.ps_pop()?;
+    vm.ps_push(binary_i32(OP, a, b))?;
+    Ok(true)
+}
+
+fn load(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.next;
+    let addr = vm.ps_pop()? as usize;
+    vm.guest_load(addr, 4)?;
+    let value = vm.read_i32(addr);
+    vm.ps_push(value)?;
+    Ok(true)
+}
+
+fn store(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.next;
+    let addr = vm.ps_pop()? as usize;
+    let value = vm.ps_pop()?;
+    vm.guest_store(addr, 4, |vm| vm.write_i32(value, addr))?;
+    Ok(true)
+}
+
+fn local_get(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.addr + 1;
+    let value = *vm.local(d.imm as u32 as usize)?;
+    vm.ps_push(value)?;
+    *ip = d.next;
+    Ok(true)
+}
+
+// LOCAL_SET, or LOCAL_TEE keeping the value
+fn local_set<const TEE: bool>(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.addr + 1;
+    let value = vm.ps_pop()?;
+    *vm.local(d.imm as u32 as usize)? = value;
+    if TEE {
+        vm.ps_push(value)?;
+    }
+    *ip = d.next;
+    Ok(true)
+}
+
+fn jump(_vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.target;
+    Ok(true)
+}
+
+fn jump_if_zero(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.addr + 1;
+    let is_zero = vm.ps_pop()? == 0;
+    *ip = if is_zero { d.target } else { d.next };
+    Ok(true)
+}
+
+fn calli(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.addr + 1;
+    vm.rs_push(d.next as i32)?;
+    *ip = d.target;
+    #[cfg(feature = "jit")]
+    vm.jit_note_call(*ip);
+    Ok(true)
+}
+
+fn ret(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.addr + 1;
+    vm.leave_handlers();
+    *ip = vm.rs_pop()? as usize;
+    Ok(true)
+}
+
+// I32_CONST n; ADD
+fn const_add(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.next;
+    let a = vm.ps_pop()?;
+    vm.ps_push(a.wrapping_add(d.imm))?;
+    Ok(true)
+}
+
+// I32_CONST addr; I32_LOAD
+fn const_load(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.next;
+    let addr = d.imm as usize;
+    vm.guest_load(addr, 4)?;
+    let value = vm.read_i32(addr);
+    vm.ps_push(value)?;
+    Ok(true)
+}
+
+// DUP; MUL
+fn dup_mul(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.next;
+    let a = vm.ps_pop()?;
+    vm.ps_push(a.wrapping_mul(a))?;
+    Ok(true)
+}
+
+// EQZ; BRZI
+fn eqz_brzi(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.addr + 1;
+    let a = vm.ps_pop()?;
+    *ip = if a != 0 { d.target } else { d.next };
+    Ok(true)
+}
+
+// EQ, LT_S, ...; BRZI
+fn compare_brzi(vm: &mut VM, ip: &mut usize, d: &Decoded) -> Result<bool> {
+    *ip = d.addr + 1;
+    let b = vm.ps_pop()?;
+    let a = vm.ps_pop()?;
+    let flag = if compare_i32(d.op, a, b) { TRUE } else { FALSE };
+    *ip = if flag == 0 { d.target } else { d.next };
+    Ok(true)
+}